@@ -3,7 +3,7 @@ use std::fs::create_dir_all;
 
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
-use cw_stablecoin::msg::{CustomResponse, ExecuteMsg, InstantiateMsg, QueryMsg};
+use cw_stablecoin::msg::{CustomResponse, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg};
 use cw_stablecoin::state::State;
 
 fn main() {
@@ -15,6 +15,7 @@ fn main() {
     export_schema(&schema_for!(InstantiateMsg), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
     export_schema(&schema_for!(State), &out_dir);
     export_schema(&schema_for!(CustomResponse), &out_dir);
 }
@@ -0,0 +1,127 @@
+use cosmwasm_std::{Addr, Deps, DepsMut, Empty, MessageInfo, Order, Response, StdResult, Storage};
+
+use crate::error::ContractError;
+use crate::msg::{RoleMembersResponse, RolesResponse};
+use crate::state::{Role, CONFIG, PENDINGOWNER, ROLES};
+
+fn holds(storage: &dyn Storage, address: &Addr, role: Role) -> bool {
+    ROLES.has(storage, (role.to_string(), address.clone()))
+}
+
+/// Fails unless `sender` is the owner, an admin or holds `role`.
+pub fn ensure_role(storage: &dyn Storage, sender: &Addr, role: Role) -> Result<(), ContractError> {
+    let config = CONFIG.load(storage)?;
+    if config.owner == *sender
+        || holds(storage, sender, Role::Admin)
+        || holds(storage, sender, role)
+    {
+        return Ok(());
+    }
+    Err(ContractError::MissingRole { role })
+}
+
+/// Admins manage every role but their own, which only the owner manages.
+fn ensure_role_manager(
+    storage: &dyn Storage,
+    sender: &Addr,
+    role: Role,
+) -> Result<(), ContractError> {
+    if role == Role::Admin {
+        let config = CONFIG.load(storage)?;
+        if config.owner != *sender {
+            return Err(ContractError::NOTOWNER {});
+        }
+        return Ok(());
+    }
+    ensure_role(storage, sender, Role::Admin)
+}
+
+pub fn execute_grant_role(
+    deps: DepsMut,
+    info: MessageInfo,
+    role: Role,
+    address: String,
+) -> Result<Response, ContractError> {
+    ensure_role_manager(deps.storage, &info.sender, role)?;
+    let address = deps.api.addr_validate(&address)?;
+    ROLES.save(deps.storage, (role.to_string(), address.clone()), &Empty {})?;
+
+    Ok(Response::new()
+        .add_attribute("action", "grant_role")
+        .add_attribute("role", role.to_string())
+        .add_attribute("address", address))
+}
+
+pub fn execute_revoke_role(
+    deps: DepsMut,
+    info: MessageInfo,
+    role: Role,
+    address: String,
+) -> Result<Response, ContractError> {
+    ensure_role_manager(deps.storage, &info.sender, role)?;
+    let address = deps.api.addr_validate(&address)?;
+    ROLES.remove(deps.storage, (role.to_string(), address.clone()));
+
+    Ok(Response::new()
+        .add_attribute("action", "revoke_role")
+        .add_attribute("role", role.to_string())
+        .add_attribute("address", address))
+}
+
+/// Offers ownership to `new_owner`. The current owner keeps it until the offer
+/// is accepted, and a new offer replaces the previous one.
+pub fn execute_transfer_ownership(
+    deps: DepsMut,
+    info: MessageInfo,
+    new_owner: String,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    if config.owner != info.sender {
+        return Err(ContractError::NOTOWNER {});
+    }
+    let new_owner = deps.api.addr_validate(&new_owner)?;
+    PENDINGOWNER.save(deps.storage, &new_owner)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "transfer_ownership")
+        .add_attribute("pending_owner", new_owner))
+}
+
+pub fn execute_accept_ownership(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    if PENDINGOWNER.may_load(deps.storage)? != Some(info.sender.clone()) {
+        return Err(ContractError::NotPendingOwner {});
+    }
+    let mut config = CONFIG.load(deps.storage)?;
+    config.owner = info.sender.clone();
+    CONFIG.save(deps.storage, &config)?;
+    PENDINGOWNER.remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("action", "accept_ownership")
+        .add_attribute("owner", info.sender))
+}
+
+pub fn query_roles(deps: Deps) -> StdResult<RolesResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let roles = Role::ALL
+        .iter()
+        .map(|role| {
+            let members = ROLES
+                .prefix(role.to_string())
+                .keys(deps.storage, None, None, Order::Ascending)
+                .collect::<StdResult<Vec<Addr>>>()?;
+            Ok(RoleMembersResponse {
+                role: *role,
+                members,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(RolesResponse {
+        owner: config.owner,
+        pending_owner: PENDINGOWNER.may_load(deps.storage)?,
+        roles,
+    })
+}
@@ -0,0 +1,195 @@
+use cosmwasm_std::{
+    Decimal, Deps, DepsMut, Env, MessageInfo, Order, Response, StdResult, Storage, Uint128,
+};
+use cw_storage_plus::Bound;
+
+use crate::contract::{burn_stable, load_collateral_type, send_asset};
+use crate::error::ContractError;
+use crate::msg::{LotResponse, LotsResponse, DEFAULT_LIMIT, MAX_LIMIT};
+use crate::oracle::refresh_price;
+use crate::pause::ensure_not_paused;
+use crate::settlement::ensure_live;
+use crate::state::{AuctionParams, Lot, PauseOp, COLLATERALTYPES, LOTCOUNT, LOTS, STABLE};
+use crate::surplus::{book_bad_debt, book_surplus};
+
+/// Price of one unit of collateral in a lot, scaled like the oracle price. It
+/// starts at `start_price` and is multiplied by `cut` every `step` seconds.
+pub fn lot_price(lot: &Lot, params: &AuctionParams, now: u64) -> Uint128 {
+    let elapsed = now.saturating_sub(lot.started_at);
+    let steps = if params.step == 0 {
+        0
+    } else {
+        elapsed / params.step
+    };
+    let factor = params
+        .cut
+        .checked_pow(steps.min(u32::MAX as u64) as u32)
+        .unwrap_or_else(|_| Decimal::zero());
+    lot.start_price * factor
+}
+
+fn is_expired(lot: &Lot, params: &AuctionParams, now: u64) -> bool {
+    now.saturating_sub(lot.started_at) > params.duration
+}
+
+/// Opens a new lot for seized collateral and returns its id.
+pub fn start_lot(storage: &mut dyn Storage, lot: &Lot) -> StdResult<u64> {
+    let lot_id = LOTCOUNT.may_load(storage)?.unwrap_or_default() + 1;
+    LOTCOUNT.save(storage, &lot_id)?;
+    LOTS.save(storage, lot_id, lot)?;
+    Ok(lot_id)
+}
+
+pub fn execute_take_lot(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    lot_id: u64,
+    amount: Uint128,
+    max_price: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    let mut lot = LOTS
+        .may_load(deps.storage, lot_id)?
+        .ok_or(ContractError::UnknownLot { lot_id })?;
+    ensure_not_paused(deps.storage, PauseOp::Liquidate, Some(&lot.collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &lot.collateral)?;
+    let now = env.block.time.seconds();
+
+    if is_expired(&lot, &collateral_type.auction, now) {
+        return Err(ContractError::AuctionExpired { lot_id });
+    }
+    let price = lot_price(&lot, &collateral_type.auction, now);
+    if price.is_zero() {
+        return Err(ContractError::AuctionExpired { lot_id });
+    }
+    if price > max_price {
+        return Err(ContractError::LotPriceTooHigh { price });
+    }
+
+    let mut slice = amount.min(lot.amount);
+    let mut owe = slice.multiply_ratio(price, Uint128::new(1000000));
+    if owe > lot.tab {
+        owe = lot.tab;
+        slice = owe
+            .multiply_ratio(Uint128::new(1000000), price)
+            .min(lot.amount);
+    }
+    if slice.is_zero() || owe.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+
+    let debt_paid = owe.min(lot.debt);
+    let penalty_paid = owe - debt_paid;
+    lot.amount -= slice;
+    lot.debt -= debt_paid;
+    lot.tab -= owe;
+
+    if !penalty_paid.is_zero() {
+        book_surplus(deps.storage, penalty_paid)?;
+    }
+
+    let token = STABLE.load(deps.storage)?;
+    let mut response = Response::new()
+        .add_message(burn_stable(info.sender.clone(), owe, token))
+        .add_message(send_asset(
+            &collateral_type.denom,
+            info.sender.clone(),
+            slice,
+        )?)
+        .add_attribute("action", "take_lot")
+        .add_attribute("lot_id", lot_id.to_string())
+        .add_attribute("price", price)
+        .add_attribute("collateral_sold", slice)
+        .add_attribute("paid", owe);
+
+    if lot.tab.is_zero() {
+        // auction raised everything owed, hand the leftover collateral back
+        if !lot.amount.is_zero() {
+            response = response
+                .add_message(send_asset(
+                    &collateral_type.denom,
+                    lot.owner.clone(),
+                    lot.amount,
+                )?)
+                .add_attribute("returned", lot.amount);
+        }
+        LOTS.remove(deps.storage, lot_id);
+    } else if lot.amount.is_zero() {
+        // collateral ran out before the debt was covered
+        if !lot.debt.is_zero() {
+            book_bad_debt(deps.storage, lot.debt)?;
+            response = response.add_attribute("bad_debt", lot.debt);
+        }
+        LOTS.remove(deps.storage, lot_id);
+    } else {
+        LOTS.save(deps.storage, lot_id, &lot)?;
+    }
+
+    Ok(response)
+}
+
+pub fn execute_reset_lot(
+    mut deps: DepsMut,
+    env: Env,
+    lot_id: u64,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    let mut lot = LOTS
+        .may_load(deps.storage, lot_id)?
+        .ok_or(ContractError::UnknownLot { lot_id })?;
+    ensure_not_paused(deps.storage, PauseOp::Liquidate, Some(&lot.collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &lot.collateral)?;
+    let now = env.block.time.seconds();
+
+    let price = lot_price(&lot, &collateral_type.auction, now);
+    if !is_expired(&lot, &collateral_type.auction, now) && !price.is_zero() {
+        return Err(ContractError::AuctionActive { lot_id });
+    }
+
+    let collateral = lot.collateral.clone();
+    let price = refresh_price(deps.branch(), &env, &collateral, &collateral_type)?;
+    lot.start_price = price * collateral_type.auction.start_multiplier;
+    lot.started_at = now;
+    LOTS.save(deps.storage, lot_id, &lot)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "reset_lot")
+        .add_attribute("lot_id", lot_id.to_string())
+        .add_attribute("start_price", lot.start_price))
+}
+
+pub fn query_lot(deps: Deps, env: Env, lot_id: u64) -> StdResult<LotResponse> {
+    let lot = LOTS.load(deps.storage, lot_id)?;
+    lot_response(deps, &env, lot_id, lot)
+}
+
+pub fn query_lots(
+    deps: Deps,
+    env: Env,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<LotsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let lots = LOTS
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+    let lots = lots
+        .into_iter()
+        .map(|(lot_id, lot)| lot_response(deps, &env, lot_id, lot))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(LotsResponse { lots })
+}
+
+fn lot_response(deps: Deps, env: &Env, lot_id: u64, lot: Lot) -> StdResult<LotResponse> {
+    let collateral_type = COLLATERALTYPES.load(deps.storage, lot.collateral.clone())?;
+    let now = env.block.time.seconds();
+    Ok(LotResponse {
+        lot_id,
+        price: lot_price(&lot, &collateral_type.auction, now),
+        expired: is_expired(&lot, &collateral_type.auction, now),
+        lot,
+    })
+}
@@ -1,27 +1,75 @@
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::{
-    coin, entry_point, to_json_binary, Binary, Coin, CosmosMsg, Deps, DepsMut, Env, MessageInfo,
-    Response, StdResult, SubMsg, WasmMsg,
+    coin, entry_point, from_json, to_json_binary, Binary, Coin, CosmosMsg, Deps, DepsMut, Env,
+    MessageInfo, Order, Reply, Response, StdError, StdResult, Storage, SubMsg, WasmMsg,
 };
-use cosmwasm_std::{Addr, BankMsg, Uint128};
-use cw2::set_contract_version;
+use cosmwasm_std::{Addr, BankMsg, Decimal, Uint128};
+use cw2::{get_contract_version, set_contract_version};
 
 use cw20_base::msg;
-use hongbai_oracle_sample::{msg::PriceResponse, msg::QueryMsg as OracleQuery};
 
-use cw0::parse_reply_instantiate_data;
+use cw0::{must_pay, parse_reply_instantiate_data};
 use cw20::Denom::Cw20;
-use cw20::{Cw20ExecuteMsg, Denom, Expiration, MinterResponse};
+use cw20::{
+    BalanceResponse, Cw20ExecuteMsg, Cw20QueryMsg, Cw20ReceiveMsg, Denom, Expiration,
+    MinterResponse,
+};
 use cw20_base::contract::query_balance;
+use cw_storage_plus::Bound;
+use semver::Version;
 use serde::de;
 
+use crate::access::{
+    ensure_role, execute_accept_ownership, execute_grant_role, execute_revoke_role,
+    execute_transfer_ownership, query_roles,
+};
+use crate::auction::{execute_reset_lot, execute_take_lot, query_lot, query_lots, start_lot};
 use crate::error::ContractError;
-use crate::msg::{ConfigResponse, ExecuteMsg, InfoResponse, InstantiateMsg, QueryMsg};
-use crate::state::{Config, COLLATERALDEPOSITED, CONFIG, STABLE, TOKENSMINTED};
+use crate::flash_mint::{execute_flash_mint, reply_flash_mint, FLASH_MINT_REPLY_ID};
+use crate::migrate::run_migrations;
+use crate::msg::{
+    CollateralTypeResponse, CollateralTypesResponse, ConfigResponse, ExecuteMsg, InfoResponse,
+    InstantiateMsg, MaxBorrowResponse, MaxWithdrawResponse, MigrateMsg, PositionsResponse,
+    QueryMsg, ReceiveMsg, SimulateLiquidationResponse, DEFAULT_LIMIT, MAX_LIMIT,
+};
+use crate::operators::{
+    execute_approve_operator, execute_revoke_operator, execute_transfer_vault, is_operator,
+    query_operators,
+};
+use crate::oracle::{
+    execute_poke, observe_price, peek_min_price, peek_twap, query_oracle_prices, query_price,
+    query_twap, refresh_min_price, refresh_twap, validate_oracle_config,
+};
+use crate::pause::{ensure_not_paused, execute_pause, execute_unpause, query_pause_state};
+use crate::psm::{
+    execute_psm_buy, execute_psm_sell, execute_set_psm_asset, query_psm_asset, query_psm_assets,
+};
+use crate::rates::{accrue, current_rate, denormalize, global_debt, normalize, normalize_ceil};
+use crate::redemption::{execute_redeem, query_redemption_rate};
+use crate::savings::{execute_exit, execute_join, query_savings_balance};
+use crate::settlement::{
+    ensure_live, execute_fix_rates, execute_redeem_settled, execute_settle_lot,
+    execute_settle_vault, execute_shutdown, execute_withdraw_excess_collateral, query_settlement,
+    redeem_settled,
+};
+use crate::stability_pool::{
+    absorb, execute_claim_collateral_gains, execute_deposit_to_pool, execute_withdraw_from_pool,
+    pool_size, query_pool_deposit, query_stability_pool,
+};
+use crate::state::{
+    positions, CollateralType, Config, Lot, OracleConfig, PauseOp, Position, Role, ACCRUEDFEES,
+    BADDEBT, COLLATERALTYPES, CONFIG, ORACLESOURCES, STABLE, TOTALDEBT, VAULTCOUNT,
+};
+use crate::surplus::{book_bad_debt, query_system_balance};
+use crate::timelock::{
+    execute_apply_update, execute_cancel_update, execute_update_config, query_pending_updates,
+};
 
 const CONTRACT_NAME: &str = "crates.io:cw-stablecoin";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+const INSTANTIATE_TOKEN_REPLY_ID: u64 = 1;
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
@@ -33,19 +81,107 @@ pub fn instantiate(
     let owner = msg.owner;
     let validate_owner = deps.api.addr_validate(&owner)?;
     let validate_oracle = deps.api.addr_validate(&msg.oracle)?;
+    let validate_treasury = deps.api.addr_validate(&msg.treasury)?;
 
     let config = Config {
         owner: validate_owner,
         oracle: validate_oracle,
-        denom: msg.denom,
-        min_threashold: msg.min_threashold,
-        liquidity_threashold: msg.liquidity_threashold,
+        treasury: validate_treasury,
         token_set: false,
+        max_price_age: msg.max_price_age,
+        max_price_deviation: msg.max_price_deviation,
+        twap_window: msg.twap_window,
+        debt_ceiling: msg.debt_ceiling,
+        settlement_delay: msg.settlement_delay,
+        update_delay: msg.update_delay,
+        flash_fee: msg.flash_fee,
+        max_flash_mint: msg.max_flash_mint,
     };
 
     CONFIG.save(deps.storage, &config)?;
+    ACCRUEDFEES.save(deps.storage, &Uint128::zero())?;
+    BADDEBT.save(deps.storage, &Uint128::zero())?;
+
+    let mut response = Response::new();
+    if let Some(token) = msg.stable_token {
+        let token_msg = cw20_base::msg::InstantiateMsg {
+            name: token.name,
+            symbol: token.symbol,
+            decimals: token.decimals,
+            initial_balances: vec![],
+            mint: Some(MinterResponse {
+                minter: env.contract.address.to_string(),
+                cap: None,
+            }),
+            marketing: None,
+        };
+        response = response.add_submessage(SubMsg::reply_on_success(
+            WasmMsg::Instantiate {
+                admin: Some(config.owner.to_string()),
+                code_id: token.code_id,
+                msg: to_json_binary(&token_msg)?,
+                funds: vec![],
+                label: "mUSD".to_string(),
+            },
+            INSTANTIATE_TOKEN_REPLY_ID,
+        ));
+    }
+
+    Ok(response)
+}
+
+/// Handles the replies to the mUSD token instantiation and to flash mint callbacks.
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    match msg.id {
+        INSTANTIATE_TOKEN_REPLY_ID => reply_instantiate_token(deps, msg),
+        FLASH_MINT_REPLY_ID => reply_flash_mint(deps, env),
+        id => Err(ContractError::UnknownReplyId { id }),
+    }
+}
+
+/// Stores the address of the mUSD token instantiated by `instantiate`.
+fn reply_instantiate_token(deps: DepsMut, msg: Reply) -> Result<Response, ContractError> {
+    let res = parse_reply_instantiate_data(msg).map_err(|_| ContractError::InstantiateFailed {})?;
+    let token = deps.api.addr_validate(&res.contract_address)?;
+
+    let mut config = CONFIG.load(deps.storage)?;
+    config.token_set = true;
+    CONFIG.save(deps.storage, &config)?;
+    STABLE.save(deps.storage, &token)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "instantiate_token")
+        .add_attribute("token", token))
+}
+
+/// Upgrades the state of a deployed vault to this version. Refuses other
+/// contracts and downgrades.
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::WrongContract {
+            contract: stored.contract,
+        });
+    }
+    let stored_version: Version = stored.version.parse()?;
+    let current_version: Version = CONTRACT_VERSION.parse()?;
+    if stored_version > current_version {
+        return Err(ContractError::Downgrade {
+            stored: stored.version,
+            current: CONTRACT_VERSION.to_string(),
+        });
+    }
 
-    Ok(Response::new())
+    let applied = run_migrations(deps.branch(), &env, &msg, &stored_version, &current_version)?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION)
+        .add_attribute("migrations", applied.to_string()))
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
@@ -56,55 +192,137 @@ pub fn execute(
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
-        ExecuteMsg::SetToken { token } => execute_set_token(deps, info, token),
-        ExecuteMsg::DepositCollateral {} => execute_deposit_collateral(deps, info),
-        ExecuteMsg::DepositCollateralAndMint { token_amount } => {
-            execute_deposit_collateral_mint(deps, info, token_amount)
+        ExecuteMsg::SetToken { token } => execute_set_token(deps, env, info, token),
+        ExecuteMsg::Receive(msg) => execute_receive(deps, env, info, msg),
+        ExecuteMsg::SetCollateralType {
+            collateral,
+            collateral_type,
+        } => execute_set_collateral_type(deps, env, info, collateral, collateral_type),
+        ExecuteMsg::SetOracleSources {
+            collateral,
+            oracle_config,
+        } => execute_set_oracle_sources(deps, info, collateral, oracle_config),
+        ExecuteMsg::CollectFees {} => execute_collect_fees(deps, env, info),
+        ExecuteMsg::Poke { collateral } => execute_poke(deps, env, collateral),
+        ExecuteMsg::UpdateConfig { update } => execute_update_config(deps, env, info, update),
+        ExecuteMsg::CancelUpdate { id } => execute_cancel_update(deps, env, info, id),
+        ExecuteMsg::ApplyUpdate { id } => execute_apply_update(deps, env, id),
+        ExecuteMsg::GrantRole { role, address } => execute_grant_role(deps, info, role, address),
+        ExecuteMsg::RevokeRole { role, address } => execute_revoke_role(deps, info, role, address),
+        ExecuteMsg::TransferOwnership { new_owner } => {
+            execute_transfer_ownership(deps, info, new_owner)
+        }
+        ExecuteMsg::AcceptOwnership {} => execute_accept_ownership(deps, info),
+        ExecuteMsg::Pause { collateral, ops } => execute_pause(deps, info, collateral, ops),
+        ExecuteMsg::Unpause { collateral, ops } => execute_unpause(deps, info, collateral, ops),
+        ExecuteMsg::Shutdown {} => execute_shutdown(deps, env, info),
+        ExecuteMsg::SettleVault { vault_id } => execute_settle_vault(deps, vault_id),
+        ExecuteMsg::SettleLot { lot_id } => execute_settle_lot(deps, lot_id),
+        ExecuteMsg::WithdrawExcessCollateral { vault_id } => {
+            execute_withdraw_excess_collateral(deps, info, vault_id)
+        }
+        ExecuteMsg::FixRates {} => execute_fix_rates(deps, env),
+        ExecuteMsg::RedeemSettled { amount } => execute_redeem_settled(deps, info, amount),
+        ExecuteMsg::OpenVault { collateral } => execute_open_vault(deps, info, collateral),
+        ExecuteMsg::CloseVault { vault_id } => execute_close_vault(deps, info, vault_id),
+        ExecuteMsg::TransferVault {
+            vault_id,
+            recipient,
+        } => execute_transfer_vault(deps, info, vault_id, recipient),
+        ExecuteMsg::ApproveOperator { operator, expires } => {
+            execute_approve_operator(deps, env, info, operator, expires)
         }
-        ExecuteMsg::RedeemCollateral { amount } => {
-            execute_redeem_collateral(deps, env, info, amount)
+        ExecuteMsg::RevokeOperator { operator } => execute_revoke_operator(deps, info, operator),
+        ExecuteMsg::DepositCollateral { vault_id } => {
+            execute_deposit_collateral(deps, env, info, vault_id)
+        }
+        ExecuteMsg::DepositCollateralAndMint {
+            vault_id,
+            token_amount,
+        } => execute_deposit_collateral_mint(deps, env, info, vault_id, token_amount),
+        ExecuteMsg::RedeemCollateral { vault_id, amount } => {
+            execute_redeem_collateral(deps, env, info, vault_id, amount)
         }
         ExecuteMsg::RedeemCollateralAndBurn {
+            vault_id,
+            amount_collateral,
+            amount_token,
+        } => execute_redeem_collateral_burn(
+            deps,
+            env,
+            info,
+            vault_id,
             amount_collateral,
             amount_token,
-        } => execute_redeem_collateral_burn(deps, env, info, amount_collateral, amount_token),
-        ExecuteMsg::Liquidate { user, amount_token } => {
-            execute_liquidation(deps, env, info, user, amount_token)
+        ),
+        ExecuteMsg::Liquidate {
+            vault_id,
+            amount_token,
+        } => execute_liquidation(deps, env, info, vault_id, amount_token),
+        ExecuteMsg::TakeLot {
+            lot_id,
+            amount,
+            max_price,
+        } => execute_take_lot(deps, env, info, lot_id, amount, max_price),
+        ExecuteMsg::ResetLot { lot_id } => execute_reset_lot(deps, env, lot_id),
+        ExecuteMsg::Redeem {
+            collateral,
+            amount,
+            max_fee_rate,
+        } => execute_redeem(deps, env, info, collateral, amount, max_fee_rate),
+        ExecuteMsg::DepositToPool { amount } => execute_deposit_to_pool(deps, env, info, amount),
+        ExecuteMsg::WithdrawFromPool { amount } => execute_withdraw_from_pool(deps, info, amount),
+        ExecuteMsg::ClaimCollateralGains {} => execute_claim_collateral_gains(deps, info),
+        ExecuteMsg::Join { amount } => execute_join(deps, env, info, amount),
+        ExecuteMsg::Exit { amount } => execute_exit(deps, env, info, amount),
+        ExecuteMsg::FlashMint { amount, callback } => {
+            execute_flash_mint(deps, info, amount, callback)
         }
-        ExecuteMsg::Swap { amount_token } => execute_swap(deps, env, info, amount_token),
-        ExecuteMsg::BorrowTokens { token_amount } => {
-            execute_borrow_tokens(deps, env, info, token_amount)
+        ExecuteMsg::SetPsmAsset { asset, psm_asset } => {
+            execute_set_psm_asset(deps, info, asset, psm_asset)
         }
-        ExecuteMsg::Repay { token_amount } => execute_repay(deps, env, info, token_amount),
+        ExecuteMsg::PsmSell { asset, amount } => execute_psm_sell(deps, env, info, asset, amount),
+        ExecuteMsg::PsmBuy { asset, amount } => execute_psm_buy(deps, info, asset, amount),
+        ExecuteMsg::BorrowTokens {
+            vault_id,
+            token_amount,
+        } => execute_borrow_tokens(deps, env, info, vault_id, token_amount),
+        ExecuteMsg::Repay {
+            vault_id,
+            token_amount,
+        } => execute_repay(deps, env, info, vault_id, token_amount),
     }
 }
 
 fn execute_borrow_tokens(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     info: MessageInfo,
+    vault_id: u64,
     amount: Uint128,
 ) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
     let user = info.sender;
-    let config = CONFIG.load(deps.storage)?;
-    let tokens = TOKENSMINTED
-        .load(deps.storage, user.clone())
-        .unwrap_or_default();
-    let collateral = COLLATERALDEPOSITED.load(deps.storage, user.clone())?;
-    let new_amount = amount + tokens;
-
-    let liquidity_threashold = config.liquidity_threashold;
+    let mut position = load_owned_position(deps.storage, vault_id, &user)?;
+    let collateral = position.collateral_type.clone();
+    ensure_not_paused(deps.storage, PauseOp::Borrow, Some(&collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let rate = accrue(deps.storage, &env, &collateral, &collateral_type)?;
+    let price = refresh_min_price(deps.branch(), &env, &collateral, &collateral_type)?;
+    let new_amount = amount + denormalize(position.debt, rate);
 
     let health_factor = calculate_health_factor(
-        calculate_collateral_usd(collateral, deps.as_ref(), config.oracle),
+        calculate_collateral_usd(position.collateral, price),
         new_amount,
-        liquidity_threashold,
+        collateral_type.liquidity_threashold,
     );
 
-    if health_factor.is_zero() {
+    if health_factor < collateral_type.min_threashold {
         return Err(ContractError::HealthFactorLess {});
     }
-    TOKENSMINTED.save(deps.storage, user.clone(), &new_amount)?;
+    position.debt += normalize_ceil(amount, rate);
+    save_position(deps.storage, vault_id, &position)?;
+    check_debt_ceilings(deps.storage, &env, &collateral, &collateral_type, rate)?;
 
     let token_addr = STABLE.load(deps.storage)?;
     let mint_msg = mint_stable(user.clone(), amount, token_addr);
@@ -116,186 +334,550 @@ fn execute_repay(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
+    vault_id: u64,
     amount: Uint128,
 ) -> Result<Response, ContractError> {
     let user = info.sender;
-    let tokens = TOKENSMINTED.load(deps.storage, user.clone())?;
-    let new_amount = tokens - amount;
-
     let token_addr = STABLE.load(deps.storage)?;
-    let burn_msg = burn_stable(user.clone(), amount, token_addr);
+    let repaid = repay(deps, &env, &user, vault_id, amount)?;
+    let burn_msg = burn_stable(user.clone(), repaid, token_addr);
 
-    TOKENSMINTED.save(deps.storage, user.clone(), &new_amount)?;
     Ok(Response::new().add_message(burn_msg))
 }
 
+/// Repays debt with mUSD sent through cw20 `Send`, burning it from the
+/// contract's own balance and returning whatever exceeds the debt.
+fn execute_receive_repay(
+    deps: DepsMut,
+    env: Env,
+    token: Addr,
+    user: Addr,
+    vault_id: u64,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let token_addr = STABLE.load(deps.storage)?;
+    if token != token_addr {
+        return Err(ContractError::UnexpectedToken {
+            token: token.to_string(),
+        });
+    }
+    let repaid = repay(deps, &env, &user, vault_id, amount)?;
+
+    let mut response = Response::new()
+        .add_message(WasmMsg::Execute {
+            contract_addr: token_addr.to_string(),
+            msg: to_json_binary(&Cw20ExecuteMsg::Burn { amount: repaid })?,
+            funds: vec![],
+        })
+        .add_attribute("action", "repay")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("repaid", repaid);
+    if amount > repaid {
+        response = response.add_message(send_asset(&Cw20(token_addr), user, amount - repaid)?);
+    }
+    Ok(response)
+}
+
+/// Books a repayment of up to `amount` mUSD by `user`, the owner or one of its
+/// operators, against the debt of a
+/// vault and returns the amount used.
+fn repay(
+    mut deps: DepsMut,
+    env: &Env,
+    user: &Addr,
+    vault_id: u64,
+    amount: Uint128,
+) -> Result<Uint128, ContractError> {
+    ensure_live(deps.storage)?;
+    let mut position = load_managed_position(deps.storage, env, vault_id, user)?;
+    let collateral = position.collateral_type.clone();
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let rate = accrue(deps.storage, env, &collateral, &collateral_type)?;
+    observe_price(deps.branch(), env, &collateral, &collateral_type);
+    let (debt, repaid) = repay_debt(position.debt, amount, rate);
+    position.debt = debt;
+
+    save_position(deps.storage, vault_id, &position)?;
+    Ok(repaid)
+}
+
 fn execute_set_token(
     deps: DepsMut,
+    env: Env,
     info: MessageInfo,
     token: Addr,
 ) -> Result<Response, ContractError> {
+    ensure_role(deps.storage, &info.sender, Role::Admin)?;
     let mut config = CONFIG.load(deps.storage)?;
-    let owner = config.owner.clone();
-    let sender = info.sender;
-    if owner != sender {
-        return Err(ContractError::NOTOWNER {});
-    } else if config.token_set {
+    if config.token_set {
         return Err(ContractError::TOKENSET {});
     }
+    let token = deps.api.addr_validate(token.as_str())?;
+    let minter: Option<MinterResponse> = deps
+        .querier
+        .query_wasm_smart(token.clone(), &Cw20QueryMsg::Minter {})?;
+    if minter.map(|minter| minter.minter) != Some(env.contract.address.to_string()) {
+        return Err(ContractError::NotTokenMinter {
+            token: token.to_string(),
+        });
+    }
     config.token_set = true;
     CONFIG.save(deps.storage, &config)?;
     STABLE.save(deps.storage, &token)?;
-    Ok(Response::new())
+    Ok(Response::new()
+        .add_attribute("action", "set_token")
+        .add_attribute("token", token))
 }
 
-fn execute_deposit_collateral(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
-    let sent_funds = info.funds;
-    let user = info.sender;
+fn invalid_collateral_type(reason: &str) -> ContractError {
+    ContractError::InvalidCollateralType {
+        reason: reason.to_string(),
+    }
+}
 
-    let config = CONFIG.load(deps.storage)?;
+/// Rejects parameters a collateral type cannot work with, whether it is being
+/// registered or changed through the timelock.
+pub(crate) fn validate_collateral_type(
+    deps: Deps,
+    collateral_type: &CollateralType,
+) -> Result<(), ContractError> {
+    let denom = match &collateral_type.denom {
+        Denom::Native(denom) => denom.clone(),
+        Denom::Cw20(token) => deps.api.addr_validate(token.as_str())?.to_string(),
+    };
+    if denom.is_empty() || collateral_type.oracle_symbol.is_empty() {
+        return Err(invalid_collateral_type(
+            "denom and oracle_symbol are required",
+        ));
+    }
+    if collateral_type.min_threashold.is_zero() {
+        return Err(invalid_collateral_type("min_threashold must be positive"));
+    }
+    if collateral_type.liquidity_threashold.is_zero() {
+        return Err(invalid_collateral_type(
+            "liquidity_threashold must be positive",
+        ));
+    }
+    if collateral_type.stability_fee >= Decimal::one() {
+        return Err(invalid_collateral_type("stability_fee must be below 1"));
+    }
+    if collateral_type.liquidation_penalty >= Decimal::one() {
+        return Err(invalid_collateral_type(
+            "liquidation_penalty must be below 1",
+        ));
+    }
+    let auction = &collateral_type.auction;
+    if auction.start_multiplier.is_zero() {
+        return Err(invalid_collateral_type(
+            "auction start_multiplier must be positive",
+        ));
+    }
+    // a cut above one would raise the lot price at every step
+    if auction.cut.is_zero() || auction.cut > Decimal::one() {
+        return Err(invalid_collateral_type(
+            "auction cut must be positive and at most 1",
+        ));
+    }
+    if auction.step == 0 || auction.duration == 0 {
+        return Err(invalid_collateral_type(
+            "auction step and duration must be positive",
+        ));
+    }
+    Ok(())
+}
 
-    let amount_sent = amount_sent(sent_funds, config.denom.clone());
-    println!("amount is {}", amount_sent);
-    let user_deposit = COLLATERALDEPOSITED
-        .load(deps.storage, user.clone())
-        .unwrap_or_default();
+fn execute_set_collateral_type(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collateral: String,
+    collateral_type: CollateralType,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    ensure_role(deps.storage, &info.sender, Role::RiskManager)?;
+    validate_collateral_type(deps.as_ref(), &collateral_type)?;
+    let denom = match &collateral_type.denom {
+        Denom::Native(denom) => denom.clone(),
+        Denom::Cw20(token) => token.to_string(),
+    };
+
+    if COLLATERALTYPES.has(deps.storage, collateral.clone()) {
+        return Err(ContractError::InvalidCollateralType {
+            reason: "already registered, change it through UpdateConfig".to_string(),
+        });
+    }
 
-    println!("user deposit is {}", user_deposit);
+    accrue(deps.storage, &env, &collateral, &collateral_type)?;
 
-    COLLATERALDEPOSITED.save(deps.storage, user.clone(), &(amount_sent + user_deposit))?;
-    println!(
-        "Deposit total is in execute_deposit_collateral {}",
-        amount_sent + user_deposit
-    );
-    Ok(Response::new().add_attribute("execute deposit", "collateral deposited"))
+    COLLATERALTYPES.save(deps.storage, collateral.clone(), &collateral_type)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_collateral_type")
+        .add_attribute("collateral", collateral)
+        .add_attribute("denom", denom))
 }
 
-fn execute_deposit_collateral_mint(
+fn execute_set_oracle_sources(
     deps: DepsMut,
     info: MessageInfo,
-    token_amount: Uint128,
+    collateral: String,
+    oracle_config: OracleConfig,
 ) -> Result<Response, ContractError> {
-    let sent_funds = info.funds;
+    ensure_role(deps.storage, &info.sender, Role::OracleManager)?;
+    load_collateral_type(deps.storage, &collateral)?;
+    validate_oracle_config(deps.as_ref(), &oracle_config)?;
+
+    if ORACLESOURCES.has(deps.storage, collateral.clone()) {
+        return Err(ContractError::InvalidOracleConfig {
+            reason: "already registered, change it through UpdateConfig".to_string(),
+        });
+    }
+    // vaults are already valued by the default oracle, which only the
+    // timelock may replace
+    if positions()
+        .idx
+        .ratio
+        .sub_prefix(collateral.clone())
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_some()
+    {
+        return Err(ContractError::InvalidOracleConfig {
+            reason: "collateral has vaults, change it through UpdateConfig".to_string(),
+        });
+    }
 
-    let user = info.sender;
+    ORACLESOURCES.save(deps.storage, collateral.clone(), &oracle_config)?;
 
+    Ok(Response::new()
+        .add_attribute("action", "set_oracle_sources")
+        .add_attribute("collateral", collateral)
+        .add_attribute("sources", oracle_config.sources.len().to_string()))
+}
+
+fn execute_collect_fees(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    ensure_role(deps.storage, &info.sender, Role::FeeCollector)?;
     let config = CONFIG.load(deps.storage)?;
+    let collateral_types = COLLATERALTYPES
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (collateral, collateral_type) in collateral_types {
+        accrue(deps.storage, &env, &collateral, &collateral_type)?;
+    }
+
+    let fees = ACCRUEDFEES.load(deps.storage)?;
+    if fees.is_zero() {
+        return Err(ContractError::NoFees {});
+    }
+    ACCRUEDFEES.save(deps.storage, &Uint128::zero())?;
 
     let token = STABLE.load(deps.storage)?;
+    let mint_msg = mint_stable(config.treasury, fees, token);
 
-    let amount_sent = amount_sent(sent_funds, config.denom.clone());
-    let user_deposit = COLLATERALDEPOSITED
-        .load(deps.storage, user.clone())
-        .unwrap_or_default();
+    Ok(Response::new()
+        .add_message(mint_msg)
+        .add_attribute("action", "collect_fees")
+        .add_attribute("amount", fees))
+}
 
-    let mut token_minted = TOKENSMINTED
-        .load(deps.storage, user.clone())
-        .unwrap_or_default();
+fn execute_receive(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    wrapper: Cw20ReceiveMsg,
+) -> Result<Response, ContractError> {
+    let user = deps.api.addr_validate(&wrapper.sender)?;
+    let amount = wrapper.amount;
+    match from_json(&wrapper.msg)? {
+        ReceiveMsg::DepositCollateral { vault_id } => {
+            check_cw20_collateral(deps.as_ref(), &info.sender, vault_id)?;
+            deposit_collateral(deps, env, user, vault_id, amount)
+        }
+        ReceiveMsg::DepositAndMint {
+            vault_id,
+            token_amount,
+        } => {
+            check_cw20_collateral(deps.as_ref(), &info.sender, vault_id)?;
+            deposit_collateral_mint(deps, env, user, vault_id, amount, token_amount)
+        }
+        ReceiveMsg::Repay { vault_id } => {
+            execute_receive_repay(deps, env, info.sender, user, vault_id, amount)
+        }
+        ReceiveMsg::RedeemSettled {} => {
+            let token_addr = STABLE.load(deps.storage)?;
+            if info.sender != token_addr {
+                return Err(ContractError::UnexpectedToken {
+                    token: info.sender.to_string(),
+                });
+            }
+            Ok(
+                redeem_settled(deps, user, amount)?.add_message(WasmMsg::Execute {
+                    contract_addr: token_addr.to_string(),
+                    msg: to_json_binary(&Cw20ExecuteMsg::Burn { amount })?,
+                    funds: vec![],
+                }),
+            )
+        }
+    }
+}
 
-    token_minted += token_amount;
+/// Rejects a cw20 deposit made with any token other than the vault collateral's own.
+fn check_cw20_collateral(deps: Deps, token: &Addr, vault_id: u64) -> Result<(), ContractError> {
+    let collateral = load_position(deps.storage, vault_id)?.collateral_type;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    if collateral_type.denom != Denom::Cw20(token.clone()) {
+        return Err(ContractError::InvalidCollateralAsset { collateral });
+    }
+    Ok(())
+}
 
-    TOKENSMINTED.save(deps.storage, user.clone(), &token_minted)?;
-    COLLATERALDEPOSITED.save(deps.storage, user.clone(), &(amount_sent + user_deposit))?;
+/// Amount of native collateral attached to the message. Cw20 collateral is
+/// deposited through `Receive` instead.
+fn collateral_sent(
+    funds: Vec<Coin>,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    match &collateral_type.denom {
+        Denom::Native(denom) => Ok(amount_sent(funds, denom.clone())),
+        Denom::Cw20(_) => Err(ContractError::InvalidCollateralAsset {
+            collateral: collateral.to_string(),
+        }),
+    }
+}
 
-    println!(
-        "Deposit total is in execute_deposit_collateral_mint {}",
-        amount_sent + user_deposit
-    );
+/// Opens an empty vault of `collateral` owned by the sender.
+fn execute_open_vault(
+    deps: DepsMut,
+    info: MessageInfo,
+    collateral: String,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    load_collateral_type(deps.storage, &collateral)?;
+    let vault_id = VAULTCOUNT.may_load(deps.storage)?.unwrap_or_default() + 1;
+    VAULTCOUNT.save(deps.storage, &vault_id)?;
+    let position = Position {
+        owner: info.sender.clone(),
+        collateral_type: collateral.clone(),
+        collateral: Uint128::zero(),
+        debt: Uint128::zero(),
+    };
+    positions().save(deps.storage, vault_id, &position)?;
 
-    let collateral = COLLATERALDEPOSITED.load(deps.storage, user.clone())?;
-    let collateral_value_usd = calculate_collateral_usd(collateral, deps.as_ref(), config.oracle);
-    let liquidity_threashold = config.liquidity_threashold;
-    let health_Factor =
-        calculate_health_factor(collateral_value_usd, token_minted, liquidity_threashold);
-    println!("Current health_Factor is {}", health_Factor);
-    if health_Factor.is_zero() {
-        return Err(ContractError::HealthFactorLess {});
+    Ok(Response::new()
+        .add_attribute("action", "open_vault")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("owner", info.sender)
+        .add_attribute("collateral", collateral))
+}
+
+/// Closes a vault without debt and returns its collateral to the owner.
+fn execute_close_vault(
+    deps: DepsMut,
+    info: MessageInfo,
+    vault_id: u64,
+) -> Result<Response, ContractError> {
+    let position = load_owned_position(deps.storage, vault_id, &info.sender)?;
+    let collateral = position.collateral_type.clone();
+    ensure_not_paused(deps.storage, PauseOp::Redeem, Some(&collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    if !position.debt.is_zero() {
+        return Err(ContractError::VaultHasDebt { vault_id });
+    }
+    positions().remove(deps.storage, vault_id)?;
+
+    let mut response = Response::new()
+        .add_attribute("action", "close_vault")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("withdrawn", position.collateral);
+    if !position.collateral.is_zero() {
+        response = response.add_message(send_asset(
+            &collateral_type.denom,
+            position.owner,
+            position.collateral,
+        )?);
     }
+    Ok(response)
+}
 
-    let msg = mint_stable(user.clone(), token_amount, token);
+fn execute_deposit_collateral(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u64,
+) -> Result<Response, ContractError> {
+    let collateral = load_position(deps.storage, vault_id)?.collateral_type;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let amount = match &collateral_type.denom {
+        Denom::Native(denom) => must_pay(&info, denom)?,
+        Denom::Cw20(_) => return Err(ContractError::InvalidCollateralAsset { collateral }),
+    };
+    deposit_collateral(deps, env, info.sender, vault_id, amount)
+}
 
-    Ok(Response::new().add_message(msg))
+fn deposit_collateral(
+    mut deps: DepsMut,
+    env: Env,
+    user: Addr,
+    vault_id: u64,
+    amount_sent: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    let mut position = load_managed_position(deps.storage, &env, vault_id, &user)?;
+    let collateral = position.collateral_type.clone();
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    observe_price(deps.branch(), &env, &collateral, &collateral_type);
+
+    position.collateral += amount_sent;
+    save_position(deps.storage, vault_id, &position)?;
+    Ok(Response::new()
+        .add_attribute("action", "deposit_collateral")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("amount", amount_sent))
 }
 
-fn execute_redeem_collateral(
+fn execute_deposit_collateral_mint(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    amount_withdraw: Uint128,
+    vault_id: u64,
+    token_amount: Uint128,
 ) -> Result<Response, ContractError> {
-    println!("withdraw req is {}", amount_withdraw);
-    let deposit = COLLATERALDEPOSITED.load(deps.storage, info.sender.clone())?;
-    let token_minted = TOKENSMINTED
-        .load(deps.storage, info.sender.clone())
-        .unwrap_or_default();
-
-    let config = CONFIG.load(deps.storage)?;
+    let collateral = load_position(deps.storage, vault_id)?.collateral_type;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let amount = collateral_sent(info.funds, &collateral, &collateral_type)?;
+    deposit_collateral_mint(deps, env, info.sender, vault_id, amount, token_amount)
+}
 
-    let liquidity_threashold = config.liquidity_threashold;
+fn deposit_collateral_mint(
+    mut deps: DepsMut,
+    env: Env,
+    user: Addr,
+    vault_id: u64,
+    amount_sent: Uint128,
+    token_amount: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    let mut position = load_owned_position(deps.storage, vault_id, &user)?;
+    let collateral = position.collateral_type.clone();
+    ensure_not_paused(deps.storage, PauseOp::Mint, Some(&collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let rate = accrue(deps.storage, &env, &collateral, &collateral_type)?;
+    let price = refresh_min_price(deps.branch(), &env, &collateral, &collateral_type)?;
 
-    let remaining_collateral =
-        calculate_collateral_usd(deposit - amount_withdraw, deps.as_ref(), config.oracle);
-    println!("remaining collateral  {}", remaining_collateral);
+    let token = STABLE.load(deps.storage)?;
 
-    let health_Factor =
-        calculate_health_factor(remaining_collateral, token_minted, liquidity_threashold);
+    position.debt += normalize_ceil(token_amount, rate);
+    position.collateral += amount_sent;
+    let token_minted = denormalize(position.debt, rate);
 
-    println!("health factor redeem req is {}", health_Factor);
+    save_position(deps.storage, vault_id, &position)?;
+    if !token_amount.is_zero() {
+        check_debt_ceilings(deps.storage, &env, &collateral, &collateral_type, rate)?;
+    }
 
-    if health_Factor.is_zero() {
+    let collateral_value_usd = calculate_collateral_usd(position.collateral, price);
+    let liquidity_threashold = collateral_type.liquidity_threashold;
+    let health_factor =
+        calculate_health_factor(collateral_value_usd, token_minted, liquidity_threashold);
+    if health_factor < collateral_type.min_threashold {
         return Err(ContractError::HealthFactorLess {});
     }
 
-    println!("withdraw req final  is {}", amount_withdraw);
+    let msg = mint_stable(user.clone(), token_amount, token);
 
-    COLLATERALDEPOSITED.save(
-        deps.storage,
-        info.sender.clone(),
-        &(deposit - amount_withdraw),
-    )?;
+    Ok(Response::new().add_message(msg))
+}
+
+fn execute_redeem_collateral(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    vault_id: u64,
+    amount_withdraw: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    let mut position = load_owned_position(deps.storage, vault_id, &info.sender)?;
+    let collateral = position.collateral_type.clone();
+    ensure_not_paused(deps.storage, PauseOp::Redeem, Some(&collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let rate = accrue(deps.storage, &env, &collateral, &collateral_type)?;
+    let price = refresh_min_price(deps.branch(), &env, &collateral, &collateral_type)?;
+    let token_minted = denormalize(position.debt, rate);
+
+    let liquidity_threashold = collateral_type.liquidity_threashold;
+
+    let new_collateral = position
+        .collateral
+        .checked_sub(amount_withdraw)
+        .map_err(|_| ContractError::InsufficientCollateral { vault_id })?;
+    let remaining_collateral = calculate_collateral_usd(new_collateral, price);
+
+    let health_factor =
+        calculate_health_factor(remaining_collateral, token_minted, liquidity_threashold);
 
-    let msg = send_native(info.sender.clone(), amount_withdraw);
+    if health_factor < collateral_type.min_threashold {
+        return Err(ContractError::HealthFactorLess {});
+    }
 
-    let contract_balance = deps
-        .querier
-        .query_balance(env.contract.address.clone(), "uom")?;
+    position.collateral = new_collateral;
+    save_position(deps.storage, vault_id, &position)?;
 
-    println!("Contract balance is {}", contract_balance.amount);
+    let msg = send_asset(&collateral_type.denom, info.sender.clone(), amount_withdraw)?;
 
     Ok(Response::new().add_message(msg))
 }
 
 fn execute_redeem_collateral_burn(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     info: MessageInfo,
+    vault_id: u64,
     amount_collateral: Uint128,
     amount_token: Uint128,
 ) -> Result<Response, ContractError> {
-    let token_minted = TOKENSMINTED.load(deps.storage, info.sender.clone())?;
-    let collateral_deposited = COLLATERALDEPOSITED.load(deps.storage, info.sender.clone())?;
-    let config = CONFIG.load(deps.storage)?;
+    ensure_live(deps.storage)?;
+    let mut position = load_owned_position(deps.storage, vault_id, &info.sender)?;
+    let collateral = position.collateral_type.clone();
+    ensure_not_paused(deps.storage, PauseOp::Redeem, Some(&collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let rate = accrue(deps.storage, &env, &collateral, &collateral_type)?;
+    let price = refresh_min_price(deps.branch(), &env, &collateral, &collateral_type)?;
     let token = STABLE.load(deps.storage)?;
 
-    let new_token = token_minted - amount_token;
-    let new_collateral = collateral_deposited - amount_collateral;
+    let (new_normalized, amount_token) = repay_debt(position.debt, amount_token, rate);
+    let new_token = denormalize(new_normalized, rate);
+    let new_collateral = position
+        .collateral
+        .checked_sub(amount_collateral)
+        .map_err(|_| ContractError::InsufficientCollateral { vault_id })?;
 
-    let liquidity_threashold = config.liquidity_threashold;
+    let liquidity_threashold = collateral_type.liquidity_threashold;
 
     let health_factor = calculate_health_factor(
-        calculate_collateral_usd(new_collateral, deps.as_ref(), config.oracle),
+        calculate_collateral_usd(new_collateral, price),
         new_token,
         liquidity_threashold,
     );
 
-    if health_factor.is_zero() {
+    if health_factor < collateral_type.min_threashold {
         return Err(ContractError::HealthFactorLess {});
     }
 
-    TOKENSMINTED.save(deps.storage, info.sender.clone(), &new_token)?;
-    COLLATERALDEPOSITED.save(deps.storage, info.sender.clone(), &new_collateral)?;
+    position.debt = new_normalized;
+    position.collateral = new_collateral;
+    save_position(deps.storage, vault_id, &position)?;
 
-    let msg = send_native(info.sender.clone(), amount_collateral);
+    let msg = send_asset(
+        &collateral_type.denom,
+        info.sender.clone(),
+        amount_collateral,
+    )?;
 
     let burn_msg = burn_stable(info.sender, amount_token, token);
 
@@ -303,68 +885,304 @@ fn execute_redeem_collateral_burn(
 }
 
 fn execute_liquidation(
-    deps: DepsMut,
+    mut deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    user: Addr,
+    vault_id: u64,
     amount: Uint128,
 ) -> Result<Response, ContractError> {
-    let collateral_deposited = COLLATERALDEPOSITED.load(deps.storage, user.clone())?;
-    let token_minted = TOKENSMINTED.load(deps.storage, user.clone())?;
-    let config = CONFIG.load(deps.storage)?;
-    let token = STABLE.load(deps.storage)?;
+    let (mut position, collateral_type) = load_liquidation(deps.storage, vault_id)?;
+    let collateral = position.collateral_type.clone();
+    let rate = accrue(deps.storage, &env, &collateral, &collateral_type)?;
+    let price = refresh_twap(deps.branch(), &env, &collateral, &collateral_type)?;
+    let pool = pool_size(deps.storage)?;
+    let liquidation = plan_liquidation(&position, &collateral_type, rate, price, amount, pool)?;
+    let debt = liquidation.debt;
+    let seized = liquidation.seized;
+
+    position.debt = liquidation.normalized_debt;
+    position.collateral -= seized;
+    save_position(deps.storage, vault_id, &position)?;
+
+    let mut response = Response::new()
+        .add_attribute("action", "liquidate")
+        .add_attribute("keeper", info.sender)
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("collateral", collateral.clone())
+        .add_attribute("debt", debt)
+        .add_attribute("seized", seized);
+
+    if !liquidation.pool_debt.is_zero() {
+        absorb(
+            deps.storage,
+            &collateral,
+            liquidation.pool_debt,
+            liquidation.pool_collateral,
+        )?;
+        let token = STABLE.load(deps.storage)?;
+        response = response
+            .add_message(WasmMsg::Execute {
+                contract_addr: token.to_string(),
+                msg: to_json_binary(&Cw20ExecuteMsg::Burn {
+                    amount: liquidation.pool_debt,
+                })?,
+                funds: vec![],
+            })
+            .add_attribute("pool_debt", liquidation.pool_debt)
+            .add_attribute("pool_collateral", liquidation.pool_collateral);
+    }
+
+    let lot_debt = debt - liquidation.pool_debt;
+    if lot_debt.is_zero() {
+        return Ok(response);
+    }
+    if liquidation.lot_collateral.is_zero() {
+        book_bad_debt(deps.storage, lot_debt)?;
+        return Ok(response.add_attribute("bad_debt", lot_debt));
+    }
+
+    let start_price = price * collateral_type.auction.start_multiplier;
+    let lot = Lot {
+        owner: position.owner,
+        collateral,
+        amount: liquidation.lot_collateral,
+        debt: lot_debt,
+        tab: lot_debt + liquidation.bonus,
+        start_price,
+        started_at: env.block.time.seconds(),
+    };
+    let lot_id = start_lot(deps.storage, &lot)?;
+
+    Ok(response
+        .add_attribute("lot_id", lot_id.to_string())
+        .add_attribute("tab", lot.tab))
+}
+
+/// Outcome of liquidating a vault, shared by `Liquidate` and
+/// `SimulateLiquidation`.
+struct Liquidation {
+    /// mUSD debt taken off the vault.
+    debt: Uint128,
+    /// Collateral taken out of the vault.
+    seized: Uint128,
+    /// Debt burned from the stability pool.
+    pool_debt: Uint128,
+    /// Collateral handed to the stability pool for `pool_debt`.
+    pool_collateral: Uint128,
+    /// Collateral moved into the lot for the rest of the debt.
+    lot_collateral: Uint128,
+    /// Penalty added to the debt the lot has to cover.
+    bonus: Uint128,
+    normalized_debt: Uint128,
+    remaining_debt: Uint128,
+    health_factor: Uint128,
+}
+
+/// Loads a vault and its collateral type for a liquidation, failing when
+/// liquidations are off for it.
+fn load_liquidation(
+    storage: &dyn Storage,
+    vault_id: u64,
+) -> Result<(Position, CollateralType), ContractError> {
+    ensure_live(storage)?;
+    let position = load_position(storage, vault_id)?;
+    ensure_not_paused(storage, PauseOp::Liquidate, Some(&position.collateral_type))?;
+    let collateral_type = load_collateral_type(storage, &position.collateral_type)?;
+    Ok((position, collateral_type))
+}
+
+/// Works out how much of a vault's debt and collateral a liquidation of up to
+/// `amount` mUSD takes at `rate` and the TWAP `price`. The stability pool,
+/// holding `pool` mUSD, absorbs as much of the debt as it can for collateral
+/// worth that debt plus the penalty; the rest goes to auction.
+fn plan_liquidation(
+    position: &Position,
+    collateral_type: &CollateralType,
+    rate: Decimal,
+    price: Uint128,
+    amount: Uint128,
+    pool: Uint128,
+) -> Result<Liquidation, ContractError> {
+    let token_minted = denormalize(position.debt, rate);
 
-    let liquidity_threashold = config.liquidity_threashold;
+    let liquidity_threashold = collateral_type.liquidity_threashold;
 
     let health_factor = calculate_health_factor(
-        calculate_collateral_usd(collateral_deposited, deps.as_ref(), config.oracle.clone()),
+        calculate_collateral_usd(position.collateral, price),
         token_minted,
         liquidity_threashold,
     );
 
-    if health_factor > Uint128::zero() {
+    if health_factor >= collateral_type.min_threashold {
         return Err(ContractError::HealthFactorSafe {});
     }
 
-    let new_amount = token_minted - amount;
+    let (new_normalized, debt) = repay_debt(position.debt, amount, rate);
+    if debt.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
 
-    let collatera_value = calculate_usd_in_collateral(amount, deps.as_ref(), config.oracle);
+    // seize collateral in proportion to the debt taken over
+    let proportional = if new_normalized.is_zero() {
+        position.collateral
+    } else {
+        position.collateral.multiply_ratio(debt, token_minted)
+    };
+    let penalty = collateral_type.liquidation_penalty;
 
-    let send_with_bonus =
-        (collatera_value * Uint128::new(10)) / Uint128::new(100) + collatera_value;
+    // the pool's share of the collateral beyond debt and penalty stays in the vault
+    let pool_debt = if proportional.is_zero() {
+        Uint128::zero()
+    } else {
+        debt.min(pool)
+    };
+    let pool_share = proportional.multiply_ratio(pool_debt, debt);
+    let pool_collateral = pool_share.min(calculate_usd_in_collateral(
+        pool_debt + pool_debt * penalty,
+        price,
+    ));
+    let lot_collateral = proportional - pool_share;
+    let bonus = if lot_collateral.is_zero() {
+        Uint128::zero()
+    } else {
+        (debt - pool_debt) * penalty
+    };
+    let seized = pool_collateral + lot_collateral;
+
+    let remaining_debt = denormalize(new_normalized, rate);
+    Ok(Liquidation {
+        debt,
+        seized,
+        pool_debt,
+        pool_collateral,
+        lot_collateral,
+        bonus,
+        normalized_debt: new_normalized,
+        remaining_debt,
+        health_factor: calculate_health_factor(
+            calculate_collateral_usd(position.collateral - seized, price),
+            remaining_debt,
+            liquidity_threashold,
+        ),
+    })
+}
 
-    let updated_collateral_value = collateral_deposited
-        .checked_sub(send_with_bonus)
-        .unwrap_or_default();
+pub(crate) fn load_collateral_type(
+    storage: &dyn Storage,
+    collateral: &str,
+) -> Result<CollateralType, ContractError> {
+    COLLATERALTYPES
+        .may_load(storage, collateral.to_string())?
+        .ok_or_else(|| ContractError::UnknownCollateral {
+            collateral: collateral.to_string(),
+        })
+}
 
-    TOKENSMINTED.save(deps.storage, user.clone(), &new_amount)?;
-    COLLATERALDEPOSITED.save(deps.storage, user.clone(), &updated_collateral_value)?;
+pub(crate) fn load_position(
+    storage: &dyn Storage,
+    vault_id: u64,
+) -> Result<Position, ContractError> {
+    positions()
+        .may_load(storage, vault_id)?
+        .ok_or(ContractError::UnknownVault { vault_id })
+}
 
-    let burn_msg = burn_stable(info.sender.clone(), amount, token);
+/// Loads a vault for a change only its owner may make.
+pub(crate) fn load_owned_position(
+    storage: &dyn Storage,
+    vault_id: u64,
+    sender: &Addr,
+) -> Result<Position, ContractError> {
+    let position = load_position(storage, vault_id)?;
+    if position.owner != *sender {
+        return Err(ContractError::NotVaultOwner { vault_id });
+    }
+    Ok(position)
+}
 
-    let send_msg = send_native(info.sender.clone(), send_with_bonus);
-    Ok(Response::new().add_message(burn_msg).add_message(send_msg))
+/// Loads a vault for a change its owner or one of the owner's operators may
+/// make.
+pub(crate) fn load_managed_position(
+    storage: &dyn Storage,
+    env: &Env,
+    vault_id: u64,
+    sender: &Addr,
+) -> Result<Position, ContractError> {
+    let position = load_position(storage, vault_id)?;
+    if position.owner != *sender && !is_operator(storage, &env.block, &position.owner, sender)? {
+        return Err(ContractError::NotVaultOperator { vault_id });
+    }
+    Ok(position)
 }
 
-fn execute_swap(
-    deps: DepsMut,
-    env: Env,
-    info: MessageInfo,
-    amount_token: Uint128,
-) -> Result<Response, ContractError> {
-    let user = info.sender;
-    let token = STABLE.load(deps.storage)?;
-    let config = CONFIG.load(deps.storage)?;
-    let burn_msg = burn_stable(user.clone(), amount_token, token);
+/// Stores a vault and keeps the debt total of its collateral type in step.
+pub(crate) fn save_position(
+    storage: &mut dyn Storage,
+    vault_id: u64,
+    position: &Position,
+) -> StdResult<()> {
+    let previous = positions()
+        .may_load(storage, vault_id)?
+        .map(|previous| previous.debt)
+        .unwrap_or_default();
+    positions().save(storage, vault_id, position)?;
+    TOTALDEBT.update(
+        storage,
+        position.collateral_type.clone(),
+        |total| -> StdResult<_> { Ok(total.unwrap_or_default() + position.debt - previous) },
+    )?;
+    Ok(())
+}
 
-    let collateral_amount = calculate_usd_in_collateral(amount_token, deps.as_ref(), config.oracle);
+/// Fails when the debt of `collateral` or the debt across the whole system is
+/// above its ceiling. Called once the new debt is booked.
+fn check_debt_ceilings(
+    storage: &dyn Storage,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+    rate: Decimal,
+) -> Result<(), ContractError> {
+    let total_normalized = TOTALDEBT
+        .may_load(storage, collateral.to_string())?
+        .unwrap_or_default();
+    if denormalize(total_normalized, rate) > collateral_type.debt_ceiling {
+        return Err(ContractError::DebtCeilingExceeded {
+            scope: collateral.to_string(),
+        });
+    }
+    check_global_debt_ceiling(storage, env)
+}
 
-    let send_msg = send_native(user.clone(), collateral_amount);
+pub(crate) fn check_global_debt_ceiling(
+    storage: &dyn Storage,
+    env: &Env,
+) -> Result<(), ContractError> {
+    let config = CONFIG.load(storage)?;
+    if global_debt(storage, env)? > config.debt_ceiling {
+        return Err(ContractError::DebtCeilingExceeded {
+            scope: "global".to_string(),
+        });
+    }
+    Ok(())
+}
 
-    Ok(Response::new().add_message(burn_msg).add_message(send_msg))
+/// Applies a repayment of `amount` mUSD to `normalized` debt. Returns the new
+/// normalized debt and the amount actually used, which is capped at the debt owed.
+pub(crate) fn repay_debt(
+    normalized: Uint128,
+    amount: Uint128,
+    rate: Decimal,
+) -> (Uint128, Uint128) {
+    let owed = denormalize(normalized, rate);
+    if amount >= owed {
+        return (Uint128::zero(), owed);
+    }
+    let reduction = normalize(amount, rate).min(normalized);
+    (normalized - reduction, amount)
 }
 
-fn calculate_health_factor(
+pub(crate) fn calculate_health_factor(
     collateral_value: Uint128,
     token_minted: Uint128,
     liquidity_threashold: Uint128,
@@ -377,7 +1195,67 @@ fn calculate_health_factor(
     return health_factor;
 }
 
-fn amount_sent(sent_funds: Vec<Coin>, denom: String) -> Uint128 {
+/// Largest debt that `collateral_value` backs at the minimum health factor,
+/// the inverse of `calculate_health_factor`.
+fn max_debt(
+    collateral_value: Uint128,
+    liquidity_threashold: Uint128,
+    min_threashold: Uint128,
+) -> Uint128 {
+    let divisor = liquidity_threashold * min_threashold;
+    if divisor.is_zero() {
+        return Uint128::MAX;
+    }
+    (collateral_value * Uint128::new(100)) / divisor
+}
+
+/// Smallest amount of collateral at `price` that still backs `debt` at the
+/// minimum health factor.
+fn min_collateral(
+    debt: Uint128,
+    price: Uint128,
+    liquidity_threashold: Uint128,
+    min_threashold: Uint128,
+) -> Uint128 {
+    let value = div_ceil(
+        debt * liquidity_threashold * min_threashold,
+        Uint128::new(100),
+    );
+    if value.is_zero() {
+        return Uint128::zero();
+    }
+    if price.is_zero() {
+        return Uint128::MAX;
+    }
+    div_ceil(value * Uint128::new(1000000), price)
+}
+
+/// Lowest price at which `collateral` still backs `debt` at the minimum health
+/// factor. Below it the vault can be liquidated. `None` without debt.
+fn liquidation_price(
+    collateral: Uint128,
+    debt: Uint128,
+    liquidity_threashold: Uint128,
+    min_threashold: Uint128,
+) -> Option<Uint128> {
+    if debt.is_zero() {
+        return None;
+    }
+    let value = div_ceil(
+        debt * liquidity_threashold * min_threashold,
+        Uint128::new(100),
+    );
+    if collateral.is_zero() {
+        return Some(Uint128::MAX);
+    }
+    Some(div_ceil(value * Uint128::new(1000000), collateral))
+}
+
+fn div_ceil(numerator: Uint128, denominator: Uint128) -> Uint128 {
+    (numerator + denominator - Uint128::new(1)) / denominator
+}
+
+pub(crate) fn amount_sent(sent_funds: Vec<Coin>, denom: String) -> Uint128 {
     let amount = sent_funds
         .iter()
         .find(|coin| coin.denom == denom)
@@ -386,28 +1264,17 @@ fn amount_sent(sent_funds: Vec<Coin>, denom: String) -> Uint128 {
     return amount;
 }
 
-fn calculate_collateral_usd(amount: Uint128, deps: Deps, oracle: Addr) -> Uint128 {
-    let price = oracle_price(oracle, deps);
+pub(crate) fn calculate_collateral_usd(amount: Uint128, price: Uint128) -> Uint128 {
     return (amount * price) / Uint128::new(1000000);
 }
 
-fn calculate_usd_in_collateral(amount: Uint128, deps: Deps, oracle: Addr) -> Uint128 {
-    let price = oracle_price(oracle, deps);
+pub(crate) fn calculate_usd_in_collateral(amount: Uint128, price: Uint128) -> Uint128 {
     return (amount * Uint128::new(1000000)) / price;
 }
 
-fn oracle_price(oracle: Addr, deps: Deps) -> Uint128 {
-    let price_msg = OracleQuery::GetPrice {
-        symbol: "OM".to_string(),
-    };
-
-    let price_response: PriceResponse = deps.querier.query_wasm_smart(oracle, &price_msg).unwrap();
-    let price: u128 = price_response.price as u128;
-    return Uint128::new(price);
-}
 // fn deposit_collateral(user: Addr, amount: Uint128, deps: DepsMut) {}
 
-fn mint_stable(recipient: Addr, mint_amount: Uint128, token: Addr) -> CosmosMsg {
+pub(crate) fn mint_stable(recipient: Addr, mint_amount: Uint128, token: Addr) -> CosmosMsg {
     let mint_msg = cw20_base::msg::ExecuteMsg::Mint {
         recipient: recipient.into(),
         amount: Uint128::from(mint_amount),
@@ -423,7 +1290,7 @@ fn mint_stable(recipient: Addr, mint_amount: Uint128, token: Addr) -> CosmosMsg
     return msg;
 }
 
-fn burn_stable(user: Addr, burn_amount: Uint128, token: Addr) -> CosmosMsg {
+pub(crate) fn burn_stable(user: Addr, burn_amount: Uint128, token: Addr) -> CosmosMsg {
     let burn_msg = cw20_base::msg::ExecuteMsg::BurnFrom {
         owner: user.into(),
         amount: burn_amount,
@@ -439,11 +1306,11 @@ fn burn_stable(user: Addr, burn_amount: Uint128, token: Addr) -> CosmosMsg {
     return msg;
 }
 
-fn send_native(recipient: Addr, amount: Uint128) -> CosmosMsg {
+pub(crate) fn send_native(recipient: Addr, amount: Uint128, denom: String) -> CosmosMsg {
     let send_msg = BankMsg::Send {
         to_address: recipient.clone().to_string(),
         amount: vec![Coin {
-            denom: "uom".to_string(),
+            denom: denom,
             amount: amount,
         }],
     };
@@ -452,55 +1319,567 @@ fn send_native(recipient: Addr, amount: Uint128) -> CosmosMsg {
     return msg;
 }
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
+pub(crate) fn send_asset(denom: &Denom, recipient: Addr, amount: Uint128) -> StdResult<CosmosMsg> {
+    match denom {
+        Denom::Native(denom) => Ok(send_native(recipient, amount, denom.clone())),
+        Denom::Cw20(token) => Ok(WasmMsg::Execute {
+            contract_addr: token.to_string(),
+            msg: to_json_binary(&Cw20ExecuteMsg::Transfer {
+                recipient: recipient.to_string(),
+                amount,
+            })?,
+            funds: vec![],
+        }
+        .into()),
+    }
+}
+
+/// Balance of `address` in a native denom or cw20 token.
+pub(crate) fn query_asset_balance(deps: Deps, denom: &Denom, address: &Addr) -> StdResult<Uint128> {
+    match denom {
+        Denom::Native(denom) => Ok(deps.querier.query_balance(address, denom)?.amount),
+        Denom::Cw20(token) => {
+            let balance: BalanceResponse = deps.querier.query_wasm_smart(
+                token,
+                &Cw20QueryMsg::Balance {
+                    address: address.to_string(),
+                },
+            )?;
+            Ok(balance.balance)
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
         QueryMsg::Config {} => to_json_binary(&query_config(deps, env)?),
-        QueryMsg::Info { user } => to_json_binary(&query_info(deps, env, user)?),
+        QueryMsg::Info { vault_id } => to_json_binary(&query_info(deps, env, vault_id)?),
+        QueryMsg::AllPositions { start_after, limit } => {
+            to_json_binary(&query_all_positions(deps, env, start_after, limit)?)
+        }
+        QueryMsg::PositionsByOwner {
+            owner,
+            start_after,
+            limit,
+        } => to_json_binary(&query_positions_by_owner(
+            deps,
+            env,
+            owner,
+            start_after,
+            limit,
+        )?),
+        QueryMsg::MaxBorrow { vault_id } => to_json_binary(&query_max_borrow(deps, env, vault_id)?),
+        QueryMsg::MaxWithdraw { vault_id } => {
+            to_json_binary(&query_max_withdraw(deps, env, vault_id)?)
+        }
+        QueryMsg::SimulateLiquidation {
+            vault_id,
+            amount_token,
+        } => to_json_binary(&query_simulate_liquidation(
+            deps,
+            env,
+            vault_id,
+            amount_token,
+        )?),
+        QueryMsg::UnsafePositions { limit } => {
+            to_json_binary(&query_unsafe_positions(deps, env, limit)?)
+        }
+        QueryMsg::CollateralType { collateral } => {
+            to_json_binary(&query_collateral_type(deps, env, collateral)?)
+        }
+        QueryMsg::CollateralTypes {} => to_json_binary(&query_collateral_types(deps, env)?),
+        QueryMsg::Lot { lot_id } => to_json_binary(&query_lot(deps, env, lot_id)?),
+        QueryMsg::Lots { start_after, limit } => {
+            to_json_binary(&query_lots(deps, env, start_after, limit)?)
+        }
+        QueryMsg::PsmAsset { asset } => to_json_binary(&query_psm_asset(deps, asset)?),
+        QueryMsg::PsmAssets {} => to_json_binary(&query_psm_assets(deps)?),
+        QueryMsg::OraclePrices { collateral } => {
+            to_json_binary(&query_oracle_prices(deps, env, collateral)?)
+        }
+        QueryMsg::Settlement {} => to_json_binary(&query_settlement(deps)?),
+        QueryMsg::PauseState {} => to_json_binary(&query_pause_state(deps)?),
+        QueryMsg::Roles {} => to_json_binary(&query_roles(deps)?),
+        QueryMsg::PendingUpdates {} => to_json_binary(&query_pending_updates(deps)?),
+        QueryMsg::StabilityPool {} => to_json_binary(&query_stability_pool(deps)?),
+        QueryMsg::PoolDeposit { depositor } => {
+            to_json_binary(&query_pool_deposit(deps, depositor)?)
+        }
+        QueryMsg::RedemptionRate {} => to_json_binary(&query_redemption_rate(deps, env)?),
+        QueryMsg::SystemBalance {} => to_json_binary(&query_system_balance(deps, env)?),
+        QueryMsg::SavingsBalance { address } => {
+            to_json_binary(&query_savings_balance(deps, env, address)?)
+        }
+        QueryMsg::Operators { owner } => to_json_binary(&query_operators(deps, env, owner)?),
     }
 }
 
 pub fn query_config(deps: Deps, env: Env) -> StdResult<ConfigResponse> {
     let config = CONFIG.load(deps.storage)?;
-    let collateral_in_contract = deps
-        .querier
-        .query_balance(env.contract.address.clone(), "uom")?;
     Ok(ConfigResponse {
         owner: config.owner,
-        total_collateral: collateral_in_contract.amount,
-        oracle_price: oracle_price(config.oracle, deps),
-        fees: Uint128::new(10),
-        liquidity_threashold: config.liquidity_threashold,
+        stable_token: STABLE.may_load(deps.storage)?,
+        oracle: config.oracle,
+        treasury: config.treasury,
+        max_price_age: config.max_price_age,
+        max_price_deviation: config.max_price_deviation,
+        twap_window: config.twap_window,
+        debt_ceiling: config.debt_ceiling,
+        settlement_delay: config.settlement_delay,
+        update_delay: config.update_delay,
+        flash_fee: config.flash_fee,
+        max_flash_mint: config.max_flash_mint,
+        total_debt: global_debt(deps.storage, &env)?,
+        accrued_fees: ACCRUEDFEES.load(deps.storage)?,
+        bad_debt: BADDEBT.load(deps.storage)?,
     })
 }
 
-pub fn query_info(deps: Deps, env: Env, user: Addr) -> StdResult<InfoResponse> {
-    let collatera_deposited = COLLATERALDEPOSITED.load(deps.storage, user.clone())?;
-    let token_minted = TOKENSMINTED.load(deps.storage, user.clone())?;
-    let config = CONFIG.load(deps.storage)?;
+pub fn query_collateral_type(
+    deps: Deps,
+    env: Env,
+    collateral: String,
+) -> StdResult<CollateralTypeResponse> {
+    let collateral_type = COLLATERALTYPES.load(deps.storage, collateral.clone())?;
+    let collateral_in_contract =
+        query_asset_balance(deps, &collateral_type.denom, &env.contract.address)?;
+    let rate = current_rate(deps.storage, &env, &collateral, &collateral_type)?.rate;
+    let total_debt = denormalize(
+        TOTALDEBT
+            .may_load(deps.storage, collateral.clone())?
+            .unwrap_or_default(),
+        rate,
+    );
+    let oracle_price = query_price(deps, &env, &collateral, &collateral_type).ok();
+    Ok(CollateralTypeResponse {
+        collateral,
+        total_collateral: collateral_in_contract,
+        total_debt,
+        rate,
+        oracle_price,
+        collateral_type,
+    })
+}
+
+pub fn query_collateral_types(deps: Deps, env: Env) -> StdResult<CollateralTypesResponse> {
+    let collaterals = COLLATERALTYPES
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<String>>>()?;
+    let collateral_types = collaterals
+        .into_iter()
+        .map(|collateral| query_collateral_type(deps, env.clone(), collateral))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(CollateralTypesResponse { collateral_types })
+}
+
+/// What `Liquidate` would do in the current block, or why it would fail.
+pub fn query_simulate_liquidation(
+    deps: Deps,
+    env: Env,
+    vault_id: u64,
+    amount: Uint128,
+) -> StdResult<SimulateLiquidationResponse> {
+    let simulation =
+        load_liquidation(deps.storage, vault_id).and_then(|(position, collateral_type)| {
+            let collateral = &position.collateral_type;
+            let rate = current_rate(deps.storage, &env, collateral, &collateral_type)?.rate;
+            let price = peek_twap(deps, &env, collateral, &collateral_type)?;
+            let pool = pool_size(deps.storage)?;
+            plan_liquidation(&position, &collateral_type, rate, price, amount, pool)
+        });
+    Ok(match simulation {
+        Ok(liquidation) => SimulateLiquidationResponse {
+            debt: liquidation.debt,
+            seized: liquidation.seized,
+            pool_debt: liquidation.pool_debt,
+            pool_collateral: liquidation.pool_collateral,
+            bonus: liquidation.bonus,
+            remaining_debt: liquidation.remaining_debt,
+            health_factor: liquidation.health_factor,
+            error: None,
+        },
+        Err(err) => SimulateLiquidationResponse {
+            error: Some(err.to_string()),
+            ..SimulateLiquidationResponse::default()
+        },
+    })
+}
+
+/// mUSD the vault owner can borrow now, limited by the health factor at the
+/// lower of spot and TWAP price, and by the debt ceilings.
+pub fn query_max_borrow(deps: Deps, env: Env, vault_id: u64) -> StdResult<MaxBorrowResponse> {
+    max_borrow(deps, &env, vault_id)
+        .map(|amount| MaxBorrowResponse { amount })
+        .map_err(|err| StdError::generic_err(err.to_string()))
+}
+
+fn max_borrow(deps: Deps, env: &Env, vault_id: u64) -> Result<Uint128, ContractError> {
+    ensure_live(deps.storage)?;
+    let position = load_position(deps.storage, vault_id)?;
+    let collateral = position.collateral_type;
+    ensure_not_paused(deps.storage, PauseOp::Borrow, Some(&collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let rate = current_rate(deps.storage, env, &collateral, &collateral_type)?.rate;
+    let price = peek_min_price(deps, env, &collateral, &collateral_type)?;
+
+    let debt = denormalize(position.debt, rate);
+    let health_room = max_debt(
+        calculate_collateral_usd(position.collateral, price),
+        collateral_type.liquidity_threashold,
+        collateral_type.min_threashold,
+    )
+    .saturating_sub(debt);
+
+    let total_normalized = TOTALDEBT
+        .may_load(deps.storage, collateral)?
+        .unwrap_or_default();
+    let ceiling_room = collateral_type
+        .debt_ceiling
+        .saturating_sub(denormalize(total_normalized, rate));
+    let global_room = CONFIG
+        .load(deps.storage)?
+        .debt_ceiling
+        .saturating_sub(global_debt(deps.storage, env)?);
+
+    Ok(health_room.min(ceiling_room).min(global_room))
+}
+
+/// Collateral the vault owner can withdraw now while keeping the minimum
+/// health factor at the lower of spot and TWAP price.
+pub fn query_max_withdraw(deps: Deps, env: Env, vault_id: u64) -> StdResult<MaxWithdrawResponse> {
+    max_withdraw(deps, &env, vault_id)
+        .map(|amount| MaxWithdrawResponse { amount })
+        .map_err(|err| StdError::generic_err(err.to_string()))
+}
+
+fn max_withdraw(deps: Deps, env: &Env, vault_id: u64) -> Result<Uint128, ContractError> {
+    ensure_live(deps.storage)?;
+    let position = load_position(deps.storage, vault_id)?;
+    let collateral = position.collateral_type;
+    ensure_not_paused(deps.storage, PauseOp::Redeem, Some(&collateral))?;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let rate = current_rate(deps.storage, env, &collateral, &collateral_type)?.rate;
+    let price = peek_min_price(deps, env, &collateral, &collateral_type)?;
+
+    let locked = min_collateral(
+        denormalize(position.debt, rate),
+        price,
+        collateral_type.liquidity_threashold,
+        collateral_type.min_threashold,
+    );
+    Ok(position.collateral.saturating_sub(locked))
+}
+
+pub fn query_info(deps: Deps, env: Env, vault_id: u64) -> StdResult<InfoResponse> {
+    let position = positions().load(deps.storage, vault_id)?;
+    position_info(deps, &env, vault_id, position)
+}
+
+/// Vaults in id order.
+pub fn query_all_positions(
+    deps: Deps,
+    env: Env,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<PositionsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let vaults = positions()
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+    positions_response(deps, &env, vaults)
+}
+
+/// Vaults of `owner` in id order, read through the owner index.
+pub fn query_positions_by_owner(
+    deps: Deps,
+    env: Env,
+    owner: String,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<PositionsResponse> {
+    let owner = deps.api.addr_validate(&owner)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start = start_after.map(Bound::exclusive);
+    let vaults = positions()
+        .idx
+        .owner
+        .prefix(owner)
+        .range(deps.storage, start, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<Vec<_>>>()?;
+    positions_response(deps, &env, vaults)
+}
+
+/// Up to `limit` vaults that `Liquidate` would accept, valued at the TWAP as
+/// liquidations do. Each collateral type is walked from its lowest collateral
+/// ratio up to the first safe vault, so only the vaults returned and one more
+/// per type are read. Types whose price cannot be looked up are skipped.
+pub fn query_unsafe_positions(
+    deps: Deps,
+    env: Env,
+    limit: Option<u32>,
+) -> StdResult<PositionsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let mut unsafe_positions = vec![];
+    let collateral_types = COLLATERALTYPES
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (collateral, collateral_type) in collateral_types {
+        if unsafe_positions.len() >= limit {
+            break;
+        }
+        let price = match query_twap(deps, &env, &collateral, &collateral_type) {
+            Ok(price) => price,
+            Err(_) => continue,
+        };
+        let rate = current_rate(deps.storage, &env, &collateral, &collateral_type)?.rate;
+        for item in positions().idx.ratio.sub_prefix(collateral.clone()).range(
+            deps.storage,
+            None,
+            None,
+            Order::Ascending,
+        ) {
+            if unsafe_positions.len() >= limit {
+                break;
+            }
+            let (vault_id, position): (u64, Position) = item?;
+            if position.debt.is_zero() {
+                break;
+            }
+            let info = position_info_at(vault_id, position, &collateral_type, rate, price);
+            if info.health_factor >= collateral_type.min_threashold {
+                break;
+            }
+            unsafe_positions.push(info);
+        }
+    }
+    Ok(PositionsResponse {
+        positions: unsafe_positions,
+    })
+}
+
+fn positions_response(
+    deps: Deps,
+    env: &Env,
+    vaults: Vec<(u64, Position)>,
+) -> StdResult<PositionsResponse> {
+    let positions = vaults
+        .into_iter()
+        .map(|(vault_id, position)| position_info(deps, env, vault_id, position))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(PositionsResponse { positions })
+}
+
+fn position_info(
+    deps: Deps,
+    env: &Env,
+    vault_id: u64,
+    position: Position,
+) -> StdResult<InfoResponse> {
+    let collateral = position.collateral_type.clone();
+    let collateral_type = COLLATERALTYPES.load(deps.storage, collateral.clone())?;
+    let rate = current_rate(deps.storage, env, &collateral, &collateral_type)?.rate;
+    let price = query_twap(deps, env, &collateral, &collateral_type)
+        .map_err(|err| StdError::generic_err(err.to_string()))?;
+    Ok(position_info_at(
+        vault_id,
+        position,
+        &collateral_type,
+        rate,
+        price,
+    ))
+}
+
+/// Values a vault at a rate index and price already looked up.
+fn position_info_at(
+    vault_id: u64,
+    position: Position,
+    collateral_type: &CollateralType,
+    rate: Decimal,
+    price: Uint128,
+) -> InfoResponse {
+    let collateral = position.collateral_type;
+    let collatera_deposited = position.collateral;
+    let token_minted = denormalize(position.debt, rate);
     let health_factor = calculate_health_factor(
-        calculate_collateral_usd(collatera_deposited, deps, config.oracle),
+        calculate_collateral_usd(collatera_deposited, price),
         token_minted,
-        config.liquidity_threashold,
+        collateral_type.liquidity_threashold,
     );
 
-    Ok(InfoResponse {
+    InfoResponse {
+        vault_id,
+        owner: position.owner,
+        collateral,
         collateral_deposited: collatera_deposited,
         total_debt: token_minted,
         health_factor: health_factor,
-    })
+        liquidation_price: liquidation_price(
+            collatera_deposited,
+            token_minted,
+            collateral_type.liquidity_threashold,
+            collateral_type.min_threashold,
+        ),
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use cosmwasm_std::{coin, Addr, Coin, Empty, Uint128};
+    use crate::msg::{
+        CollateralGainResponse, LegacyParams, LotsResponse, OperatorResponse, OperatorsResponse,
+        OraclePricesResponse, PauseStateResponse, PendingUpdatesResponse, PoolDepositResponse,
+        PsmAssetResponse, RedemptionRateResponse, RolesResponse, SavingsBalanceResponse,
+        SettlementResponse, StabilityPoolResponse, StableTokenInfo, SystemBalanceResponse,
+    };
+    use crate::oracle::FeedPrice;
+    use crate::state::{
+        Aggregation, AuctionParams, ConfigUpdate, OracleAdapter, OracleSource, PsmAsset,
+        OBSERVATIONS,
+    };
+    use crate::surplus::book_surplus;
+    use crate::twap::{record_observation, twap_price};
+    use cosmwasm_std::{coin, Addr, Coin, ContractResult, Empty, SystemResult, Uint128};
+    use cw0::PaymentError;
     use cw20_base::contract;
     use cw_multi_test::{App, Contract, ContractWrapper, Executor};
+    use cw_storage_plus::{Item, Map};
+    use schemars::JsonSchema;
+    use serde::{Deserialize, Serialize};
 
     use super::*;
 
+    const MOCKPRICES: Map<String, Uint128> = Map::new("mockprices");
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    #[serde(rename_all = "snake_case")]
+    enum MockOracleExecuteMsg {
+        SetPrice { symbol: String, price: Uint128 },
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    #[serde(rename_all = "snake_case")]
+    enum MockOracleQueryMsg {
+        GetPrice { symbol: String },
+    }
+
+    fn mock_oracle() -> Box<dyn Contract<Empty>> {
+        let contract = ContractWrapper::new(
+            |deps: DepsMut, _env: Env, _info: MessageInfo, msg: MockOracleExecuteMsg| {
+                match msg {
+                    MockOracleExecuteMsg::SetPrice { symbol, price } => {
+                        MOCKPRICES.save(deps.storage, symbol, &price)?;
+                    }
+                }
+                Ok::<_, StdError>(Response::new())
+            },
+            |_deps: DepsMut, _env: Env, _info: MessageInfo, _msg: Empty| {
+                Ok::<_, StdError>(Response::new())
+            },
+            |deps: Deps, env: Env, msg: MockOracleQueryMsg| match msg {
+                MockOracleQueryMsg::GetPrice { symbol } => to_json_binary(&FeedPrice {
+                    price: MOCKPRICES.load(deps.storage, symbol)?.u128(),
+                    timestamp: Some(env.block.time.seconds()),
+                }),
+            },
+        );
+        Box::new(contract)
+    }
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+    #[serde(rename_all = "snake_case")]
+    enum MockBorrowerExecuteMsg {
+        Borrow {
+            engine: String,
+            token: String,
+            amount: Uint128,
+            repay: bool,
+        },
+        Callback {
+            engine: String,
+            token: String,
+            owed: Uint128,
+            repay: bool,
+        },
+    }
+
+    /// Flash mints through `Borrow` and, when asked to, leaves the vault an
+    /// allowance for the amount plus a 1% fee in the callback.
+    fn mock_borrower() -> Box<dyn Contract<Empty>> {
+        let contract = ContractWrapper::new(
+            |_deps: DepsMut, _env: Env, _info: MessageInfo, msg: MockBorrowerExecuteMsg| {
+                let msg: CosmosMsg = match msg {
+                    MockBorrowerExecuteMsg::Borrow {
+                        engine,
+                        token,
+                        amount,
+                        repay,
+                    } => WasmMsg::Execute {
+                        contract_addr: engine.clone(),
+                        msg: to_json_binary(&ExecuteMsg::FlashMint {
+                            amount,
+                            callback: to_json_binary(&MockBorrowerExecuteMsg::Callback {
+                                engine,
+                                token,
+                                owed: amount + amount * Decimal::percent(1),
+                                repay,
+                            })?,
+                        })?,
+                        funds: vec![],
+                    }
+                    .into(),
+                    MockBorrowerExecuteMsg::Callback {
+                        engine,
+                        token,
+                        owed,
+                        repay,
+                    } => {
+                        if !repay {
+                            return Ok(Response::new());
+                        }
+                        WasmMsg::Execute {
+                            contract_addr: token,
+                            msg: to_json_binary(&cw20_base::msg::ExecuteMsg::IncreaseAllowance {
+                                spender: engine,
+                                amount: owed,
+                                expires: None,
+                            })?,
+                            funds: vec![],
+                        }
+                        .into()
+                    }
+                };
+                Ok::<_, StdError>(Response::new().add_message(msg))
+            },
+            |_deps: DepsMut, _env: Env, _info: MessageInfo, _msg: Empty| {
+                Ok::<_, StdError>(Response::new())
+            },
+            |_deps: Deps, _env: Env, _msg: Empty| to_json_binary(&Empty {}),
+        );
+        Box::new(contract)
+    }
+
+    fn set_oracle_price(app: &mut App, stable_engine: Addr, symbol: &str, price: u128) {
+        let config: ConfigResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine, &QueryMsg::Config {})
+            .unwrap();
+        app.execute_contract(
+            Addr::unchecked("oracle_owner"),
+            config.oracle,
+            &MockOracleExecuteMsg::SetPrice {
+                symbol: symbol.to_string(),
+                price: Uint128::new(price),
+            },
+            &[],
+        )
+        .unwrap();
+    }
+
     fn stable_coin_contract() -> Box<dyn Contract<Empty>> {
-        let contract = ContractWrapper::new(execute, instantiate, query);
+        let contract = ContractWrapper::new(execute, instantiate, query).with_reply(reply);
         Box::new(contract)
     }
 
@@ -520,17 +1899,48 @@ mod tests {
         .unwrap();
     }
 
-    fn deploy_stable_contract(cw20_id: u64, stable_id: u64, app: &mut App, sender: Addr) -> Addr {
+    fn om_collateral_type() -> CollateralType {
+        CollateralType {
+            denom: Denom::Native("uom".to_string()),
+            oracle_symbol: "OM".to_string(),
+            min_threashold: Uint128::new(1),
+            liquidity_threashold: Uint128::new(129),
+            debt_ceiling: Uint128::new(1_000_000_000),
+            stability_fee: Decimal::percent(2),
+            liquidation_penalty: Decimal::percent(13),
+            auction: AuctionParams {
+                start_multiplier: Decimal::percent(120),
+                step: 90,
+                cut: Decimal::percent(99),
+                duration: 3600,
+            },
+        }
+    }
+
+    fn deploy_stable_contract(
+        cw20_id: u64,
+        stable_id: u64,
+        app: &mut App,
+        sender: Addr,
+        oracle: Addr,
+    ) -> Addr {
         let contract_addrss = app
             .instantiate_contract(
                 stable_id,
                 sender.clone(),
                 &InstantiateMsg {
                     owner: sender.to_string(),
-                    oracle: Addr::unchecked("oracle").to_string(),
-                    denom: "uom".to_string(),
-                    min_threashold: Uint128::new(1),
-                    liquidity_threashold: Uint128::new(129),
+                    oracle: oracle.to_string(),
+                    treasury: Addr::unchecked("treasury").to_string(),
+                    max_price_age: 3600,
+                    max_price_deviation: Decimal::percent(50),
+                    twap_window: 1800,
+                    debt_ceiling: Uint128::new(1_000_000_000_000),
+                    settlement_delay: 3600,
+                    update_delay: 86400,
+                    flash_fee: Decimal::percent(1),
+                    max_flash_mint: Uint128::new(1_000_000),
+                    stable_token: None,
                 },
                 &[],
                 "StableEngine",
@@ -591,8 +2001,21 @@ mod tests {
             println!("{}: {}", coin.denom, coin.amount);
         }
 
+        let oracle_id = app.store_code(mock_oracle());
+        let oracle = app
+            .instantiate_contract(
+                oracle_id,
+                owner_addr.clone(),
+                &Empty {},
+                &[],
+                "Oracle",
+                None,
+            )
+            .unwrap();
+
         let stable_engine =
-            deploy_stable_contract(cw20_id, stable_engine, &mut app, owner_addr.clone());
+            deploy_stable_contract(cw20_id, stable_engine, &mut app, owner_addr.clone(), oracle);
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 2_000_000);
 
         let contract_addrss =
             deploy_cw20_contract(cw20_id, stable_engine.clone(), &mut app, owner_addr.clone());
@@ -604,9 +2027,45 @@ mod tests {
         let response = app
             .execute_contract(owner_addr.clone(), stable_engine.clone(), &execut_msg, &[])
             .unwrap();
+
+        let collateral_msg = ExecuteMsg::SetCollateralType {
+            collateral: "om".to_string(),
+            collateral_type: om_collateral_type(),
+        };
+
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &collateral_msg,
+            &[],
+        )
+        .unwrap();
+        open_vault(&mut app, stable_engine.clone(), user_addr.clone(), "om");
         return (app, stable_engine, contract_addrss);
     }
 
+    fn open_vault(app: &mut App, stable_engine: Addr, owner: Addr, collateral: &str) -> u64 {
+        let response = app
+            .execute_contract(
+                owner,
+                stable_engine,
+                &ExecuteMsg::OpenVault {
+                    collateral: collateral.to_string(),
+                },
+                &[],
+            )
+            .unwrap();
+        response
+            .events
+            .iter()
+            .flat_map(|event| event.attributes.iter())
+            .find(|attribute| attribute.key == "vault_id")
+            .unwrap()
+            .value
+            .parse()
+            .unwrap()
+    }
+
     fn get_cw20_balance(owner_addr: Addr, app: App, contract_addrss: Addr) -> (Uint128, App) {
         let mut qur_msg = cw20_base::msg::QueryMsg::Balance {
             address: owner_addr.to_string(),
@@ -635,6 +2094,7 @@ mod tests {
         println!("balance in stable is {}", balance);
 
         let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
             token_amount: Uint128::from(1000u128),
         };
 
@@ -648,6 +2108,7 @@ mod tests {
             .unwrap();
 
         let dep_msg2 = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
             token_amount: Uint128::from(1015u128),
         };
 
@@ -659,6 +2120,102 @@ mod tests {
         println!("balance in stable after is {}", balance);
     }
 
+    #[test]
+    fn test_instantiate_token() {
+        let mut app = App::default();
+        let owner_addr = Addr::unchecked("owner");
+
+        let cw20_id = app.store_code(cw20_stable());
+        let stable_id = app.store_code(stable_coin_contract());
+        let oracle_id = app.store_code(mock_oracle());
+        let oracle = app
+            .instantiate_contract(
+                oracle_id,
+                owner_addr.clone(),
+                &Empty {},
+                &[],
+                "Oracle",
+                None,
+            )
+            .unwrap();
+
+        let stable_engine = app
+            .instantiate_contract(
+                stable_id,
+                owner_addr.clone(),
+                &InstantiateMsg {
+                    owner: owner_addr.to_string(),
+                    oracle: oracle.to_string(),
+                    treasury: Addr::unchecked("treasury").to_string(),
+                    max_price_age: 3600,
+                    max_price_deviation: Decimal::percent(50),
+                    twap_window: 1800,
+                    debt_ceiling: Uint128::new(1_000_000_000_000),
+                    settlement_delay: 3600,
+                    update_delay: 86400,
+                    flash_fee: Decimal::percent(1),
+                    max_flash_mint: Uint128::new(1_000_000),
+                    stable_token: Some(StableTokenInfo {
+                        code_id: cw20_id,
+                        name: "mUSD".to_string(),
+                        symbol: "MUSD".to_string(),
+                        decimals: 6,
+                    }),
+                },
+                &[],
+                "StableEngine",
+                None,
+            )
+            .unwrap();
+
+        let config: ConfigResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Config {})
+            .unwrap();
+        let token = config.stable_token.unwrap();
+        let minter: MinterResponse = app
+            .wrap()
+            .query_wasm_smart(token.clone(), &cw20_base::msg::QueryMsg::Minter {})
+            .unwrap();
+        assert_eq!(minter.minter, stable_engine.to_string());
+
+        let err = app
+            .execute_contract(
+                owner_addr.clone(),
+                stable_engine.clone(),
+                &ExecuteMsg::SetToken { token },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::TOKENSET {}.to_string()
+        );
+
+        // the fallback only accepts a token the vault can mint
+        let stable_engine =
+            deploy_stable_contract(cw20_id, stable_id, &mut app, owner_addr.clone(), oracle);
+        let foreign_token =
+            deploy_cw20_contract(cw20_id, owner_addr.clone(), &mut app, owner_addr.clone());
+        let err = app
+            .execute_contract(
+                owner_addr.clone(),
+                stable_engine.clone(),
+                &ExecuteMsg::SetToken {
+                    token: foreign_token.clone(),
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::NotTokenMinter {
+                token: foreign_token.to_string(),
+            }
+            .to_string()
+        );
+    }
+
     #[test]
     fn test_deposit_and_mint() {
         let oldapp = App::default();
@@ -670,6 +2227,7 @@ mod tests {
             deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
 
         let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
             token_amount: Uint128::from(1000u128),
         };
 
@@ -682,7 +2240,7 @@ mod tests {
             )
             .unwrap();
 
-        let redeem_msg = ExecuteMsg::DepositCollateral {};
+        let redeem_msg = ExecuteMsg::DepositCollateral { vault_id: 1 };
 
         let mut balances = app.wrap().query_all_balances(&user_addr).unwrap();
 
@@ -713,9 +2271,7 @@ mod tests {
 
         println!("query succesfull {:?}", config_response);
 
-        let quer_msg_info = QueryMsg::Info {
-            user: user_addr.clone(),
-        };
+        let quer_msg_info = QueryMsg::Info { vault_id: 1 };
         let info_response: InfoResponse = app
             .wrap()
             .query_wasm_smart(stable_engine.clone(), &quer_msg_info)
@@ -735,6 +2291,7 @@ mod tests {
             deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
 
         let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
             token_amount: Uint128::from(1000u128),
         };
 
@@ -748,6 +2305,7 @@ mod tests {
             .unwrap();
 
         let redeem_msg = ExecuteMsg::RedeemCollateral {
+            vault_id: 1,
             amount: Uint128::new(655),
         };
 
@@ -768,6 +2326,2865 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_deposit_collateral_funds() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+        mint_native(&mut app, user_addr.to_string(), "uusdc".to_string(), 1000);
+
+        let dep_msg = ExecuteMsg::DepositCollateral { vault_id: 1 };
+        let invalid_funds = vec![
+            (vec![], PaymentError::NoFunds {}),
+            (
+                vec![coin(100, "uom"), coin(100, "uusdc")],
+                PaymentError::MultipleDenoms {},
+            ),
+            (
+                vec![coin(100, "uusdc")],
+                PaymentError::MissingDenom("uom".to_string()),
+            ),
+        ];
+        for (funds, payment_err) in invalid_funds {
+            let err = app
+                .execute_contract(user_addr.clone(), stable_engine.clone(), &dep_msg, &funds)
+                .unwrap_err();
+            assert_eq!(
+                err.downcast::<ContractError>().unwrap().to_string(),
+                ContractError::Payment(payment_err).to_string()
+            );
+        }
+
+        let res = app
+            .execute_contract(
+                user_addr.clone(),
+                stable_engine.clone(),
+                &dep_msg,
+                &vec![coin(100, "uom")],
+            )
+            .unwrap();
+        assert!(res
+            .events
+            .iter()
+            .flat_map(|event| event.attributes.iter())
+            .any(|attribute| attribute.key == "action" && attribute.value == "deposit_collateral"));
+    }
+
+    #[test]
+    fn test_over_withdraw() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateral { vault_id: 1 };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1000, "uom")],
+        )
+        .unwrap();
+
+        let redeem_msg = ExecuteMsg::RedeemCollateral {
+            vault_id: 1,
+            amount: Uint128::new(1001),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &redeem_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InsufficientCollateral { vault_id: 1 }.to_string()
+        );
+
+        let burn_msg = ExecuteMsg::RedeemCollateralAndBurn {
+            vault_id: 1,
+            amount_collateral: Uint128::new(1001),
+            amount_token: Uint128::zero(),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &burn_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InsufficientCollateral { vault_id: 1 }.to_string()
+        );
+
+        // the whole deposit can still be taken back
+        let redeem_msg = ExecuteMsg::RedeemCollateral {
+            vault_id: 1,
+            amount: Uint128::new(1000),
+        };
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &redeem_msg, &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_collateral_registry() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let set_msg = ExecuteMsg::SetCollateralType {
+            collateral: "atom".to_string(),
+            collateral_type: CollateralType {
+                denom: Denom::Native("uatom".to_string()),
+                oracle_symbol: "ATOM".to_string(),
+                ..om_collateral_type()
+            },
+        };
+
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::MissingRole {
+                role: Role::RiskManager,
+            }
+            .to_string()
+        );
+
+        let open_msg = ExecuteMsg::OpenVault {
+            collateral: "atom".to_string(),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &open_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::UnknownCollateral {
+                collateral: "atom".to_string()
+            }
+            .to_string()
+        );
+
+        let auction = om_collateral_type().auction;
+        let invalid_types = vec![
+            (
+                CollateralType {
+                    min_threashold: Uint128::zero(),
+                    ..om_collateral_type()
+                },
+                "min_threashold must be positive",
+            ),
+            (
+                CollateralType {
+                    liquidation_penalty: Decimal::one(),
+                    ..om_collateral_type()
+                },
+                "liquidation_penalty must be below 1",
+            ),
+            (
+                CollateralType {
+                    auction: AuctionParams {
+                        duration: 0,
+                        ..auction.clone()
+                    },
+                    ..om_collateral_type()
+                },
+                "auction step and duration must be positive",
+            ),
+            (
+                CollateralType {
+                    auction: AuctionParams {
+                        start_multiplier: Decimal::zero(),
+                        ..auction
+                    },
+                    ..om_collateral_type()
+                },
+                "auction start_multiplier must be positive",
+            ),
+        ];
+        for (collateral_type, reason) in invalid_types {
+            let msg = ExecuteMsg::SetCollateralType {
+                collateral: "atom".to_string(),
+                collateral_type,
+            };
+            let err = app
+                .execute_contract(owner_addr.clone(), stable_engine.clone(), &msg, &[])
+                .unwrap_err();
+            assert_eq!(
+                err.downcast::<ContractError>().unwrap().to_string(),
+                ContractError::InvalidCollateralType {
+                    reason: reason.to_string(),
+                }
+                .to_string()
+            );
+        }
+
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap();
+
+        let dep_msg = ExecuteMsg::DepositCollateral { vault_id: 1 };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(100, "uom")],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_numbered_vaults() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(1000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        // a second vault of the same collateral is risked on its own
+        let vault_id = open_vault(&mut app, stable_engine.clone(), user_addr.clone(), "om");
+        assert_eq!(vault_id, 2);
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::DepositCollateral { vault_id },
+            &vec![coin(500, "uom")],
+        )
+        .unwrap();
+        let info: InfoResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Info { vault_id })
+            .unwrap();
+        assert_eq!(info.owner, user_addr);
+        assert_eq!(info.collateral_deposited, Uint128::new(500));
+        assert_eq!(info.total_debt, Uint128::zero());
+
+        let borrow_msg = ExecuteMsg::BorrowTokens {
+            vault_id,
+            token_amount: Uint128::new(10),
+        };
+        let err = app
+            .execute_contract(owner_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::NotVaultOwner { vault_id }.to_string()
+        );
+
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                stable_engine.clone(),
+                &ExecuteMsg::CloseVault { vault_id: 1 },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::VaultHasDebt { vault_id: 1 }.to_string()
+        );
+
+        let before = app.wrap().query_balance(&user_addr, "uom").unwrap();
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::CloseVault { vault_id },
+            &[],
+        )
+        .unwrap();
+        let after = app.wrap().query_balance(&user_addr, "uom").unwrap();
+        assert_eq!(after.amount - before.amount, Uint128::new(500));
+        app.wrap()
+            .query_wasm_smart::<InfoResponse>(stable_engine.clone(), &QueryMsg::Info { vault_id })
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_vault_operators() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+        let bot_addr = Addr::unchecked("bot");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+        mint_native(&mut app, bot_addr.to_string(), "uom".to_string(), 1000u128);
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(1000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let top_up = ExecuteMsg::DepositCollateral { vault_id: 1 };
+        let err = app
+            .execute_contract(
+                bot_addr.clone(),
+                stable_engine.clone(),
+                &top_up,
+                &vec![coin(200, "uom")],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::NotVaultOperator { vault_id: 1 }.to_string()
+        );
+
+        let expires = Expiration::AtHeight(app.block_info().height + 10);
+        let approve_msg = ExecuteMsg::ApproveOperator {
+            operator: bot_addr.to_string(),
+            expires: Some(expires),
+        };
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &approve_msg, &[])
+            .unwrap();
+        let operators: OperatorsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::Operators {
+                    owner: user_addr.to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            operators.operators,
+            vec![OperatorResponse {
+                operator: bot_addr.clone(),
+                expires,
+            }]
+        );
+
+        // operators top up collateral but cannot borrow against it
+        app.execute_contract(
+            bot_addr.clone(),
+            stable_engine.clone(),
+            &top_up,
+            &vec![coin(200, "uom")],
+        )
+        .unwrap();
+        let info: InfoResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Info { vault_id: 1 })
+            .unwrap();
+        assert_eq!(info.collateral_deposited, Uint128::new(1500));
+
+        let borrow_msg = ExecuteMsg::BorrowTokens {
+            vault_id: 1,
+            token_amount: Uint128::new(10),
+        };
+        let err = app
+            .execute_contract(bot_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::NotVaultOwner { vault_id: 1 }.to_string()
+        );
+
+        app.update_block(|block| block.height += 10);
+        let err = app
+            .execute_contract(
+                bot_addr.clone(),
+                stable_engine.clone(),
+                &top_up,
+                &vec![coin(200, "uom")],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::NotVaultOperator { vault_id: 1 }.to_string()
+        );
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &approve_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InvalidExpiration {}.to_string()
+        );
+
+        let transfer_msg = ExecuteMsg::TransferVault {
+            vault_id: 1,
+            recipient: bot_addr.to_string(),
+        };
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &transfer_msg, &[])
+            .unwrap();
+        let info: InfoResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Info { vault_id: 1 })
+            .unwrap();
+        assert_eq!(info.owner, bot_addr);
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::NotVaultOwner { vault_id: 1 }.to_string()
+        );
+        app.execute_contract(bot_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_stability_fee_rate() {
+        let mut storage = cosmwasm_std::testing::MockStorage::new();
+        let mut env = cosmwasm_std::testing::mock_env();
+        let collateral_type = om_collateral_type();
+
+        TOTALDEBT
+            .save(&mut storage, "om".to_string(), &Uint128::new(1_000_000))
+            .unwrap();
+        ACCRUEDFEES.save(&mut storage, &Uint128::zero()).unwrap();
+        assert_eq!(
+            accrue(&mut storage, &env, "om", &collateral_type).unwrap(),
+            Decimal::one()
+        );
+
+        env.block.time = env.block.time.plus_seconds(crate::rates::SECONDS_PER_YEAR);
+        let rate = accrue(&mut storage, &env, "om", &collateral_type).unwrap();
+        assert_eq!(rate, Decimal::percent(102));
+        assert_eq!(ACCRUEDFEES.load(&storage).unwrap(), Uint128::new(20_000));
+
+        let normalized = normalize_ceil(Uint128::new(1001), rate);
+        assert!(denormalize(normalized, rate) >= Uint128::new(1001));
+        assert_eq!(
+            repay_debt(normalized, Uint128::new(5000), rate),
+            (Uint128::zero(), denormalize(normalized, rate))
+        );
+    }
+
+    #[test]
+    fn test_surplus_cancels_bad_debt() {
+        let mut storage = cosmwasm_std::testing::MockStorage::new();
+        ACCRUEDFEES.save(&mut storage, &Uint128::zero()).unwrap();
+        BADDEBT.save(&mut storage, &Uint128::zero()).unwrap();
+
+        assert_eq!(
+            book_surplus(&mut storage, Uint128::new(100)).unwrap(),
+            Uint128::zero()
+        );
+        assert_eq!(
+            book_bad_debt(&mut storage, Uint128::new(150)).unwrap(),
+            Uint128::new(100)
+        );
+        assert_eq!(ACCRUEDFEES.load(&storage).unwrap(), Uint128::zero());
+        assert_eq!(BADDEBT.load(&storage).unwrap(), Uint128::new(50));
+
+        assert_eq!(
+            book_surplus(&mut storage, Uint128::new(80)).unwrap(),
+            Uint128::new(50)
+        );
+        assert_eq!(ACCRUEDFEES.load(&storage).unwrap(), Uint128::new(30));
+        assert_eq!(BADDEBT.load(&storage).unwrap(), Uint128::zero());
+    }
+
+    #[test]
+    fn test_position_limits() {
+        // 1300 collateral at 2.0 is worth 2600 mUSD
+        let value = calculate_collateral_usd(Uint128::new(1300), Uint128::new(2_000_000));
+        let debt = max_debt(value, Uint128::new(129), Uint128::new(1));
+        assert_eq!(debt, Uint128::new(2015));
+        assert_eq!(
+            calculate_health_factor(value, debt, Uint128::new(129)),
+            Uint128::new(1)
+        );
+        assert!(
+            calculate_health_factor(value, debt + Uint128::new(1), Uint128::new(129)).is_zero()
+        );
+
+        assert_eq!(
+            min_collateral(
+                debt,
+                Uint128::new(2_000_000),
+                Uint128::new(129),
+                Uint128::new(1)
+            ),
+            Uint128::new(1300)
+        );
+        assert_eq!(
+            liquidation_price(Uint128::new(1300), debt, Uint128::new(129), Uint128::new(1)),
+            Some(Uint128::new(2_000_000))
+        );
+        let below = calculate_collateral_usd(Uint128::new(1300), Uint128::new(1_999_999));
+        assert!(calculate_health_factor(below, debt, Uint128::new(129)).is_zero());
+        assert_eq!(
+            liquidation_price(
+                Uint128::new(1300),
+                Uint128::zero(),
+                Uint128::new(129),
+                Uint128::new(1)
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_migrate_positions() {
+        #[derive(Serialize, Deserialize)]
+        struct LegacyConfig {
+            owner: Addr,
+            oracle: Addr,
+            denom: String,
+            min_threashold: Uint128,
+            liquidity_threashold: Uint128,
+            token_set: bool,
+        }
+
+        let mut deps = cosmwasm_std::testing::mock_dependencies();
+        let env = cosmwasm_std::testing::mock_env();
+        let legacy_config: Item<LegacyConfig> = Item::new("config");
+        let deposited: Map<Addr, Uint128> = Map::new("collateradeposited");
+        let minted: Map<Addr, Uint128> = Map::new("tokensminted");
+        let user_addr = Addr::unchecked("sender");
+
+        legacy_config
+            .save(
+                &mut deps.storage,
+                &LegacyConfig {
+                    owner: Addr::unchecked("owner"),
+                    oracle: Addr::unchecked("oracle"),
+                    denom: "uom".to_string(),
+                    min_threashold: Uint128::new(1),
+                    liquidity_threashold: Uint128::new(129),
+                    token_set: true,
+                },
+            )
+            .unwrap();
+        STABLE
+            .save(&mut deps.storage, &Addr::unchecked("musd"))
+            .unwrap();
+        deposited
+            .save(&mut deps.storage, user_addr.clone(), &Uint128::new(1300))
+            .unwrap();
+        minted
+            .save(&mut deps.storage, user_addr.clone(), &Uint128::new(1000))
+            .unwrap();
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "0.1.0").unwrap();
+
+        let err = migrate(deps.as_mut(), env.clone(), MigrateMsg { legacy: None }).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::LegacyParamsRequired {}.to_string()
+        );
+
+        let om = om_collateral_type();
+        let migrate_msg = MigrateMsg {
+            legacy: Some(LegacyParams {
+                treasury: "treasury".to_string(),
+                max_price_age: 3600,
+                max_price_deviation: Decimal::percent(50),
+                twap_window: 1800,
+                debt_ceiling: Uint128::new(1_000_000_000_000),
+                settlement_delay: 3600,
+                update_delay: 86400,
+                flash_fee: Decimal::percent(1),
+                max_flash_mint: Uint128::new(1_000_000),
+                collateral: "om".to_string(),
+                collateral_debt_ceiling: om.debt_ceiling,
+                liquidation_penalty: om.liquidation_penalty,
+                auction: om.auction.clone(),
+            }),
+        };
+        migrate(deps.as_mut(), env.clone(), migrate_msg).unwrap();
+        assert_eq!(
+            positions().load(&deps.storage, 1).unwrap(),
+            Position {
+                owner: user_addr.clone(),
+                collateral_type: "om".to_string(),
+                collateral: Uint128::new(1300),
+                debt: Uint128::new(1000),
+            }
+        );
+        assert_eq!(VAULTCOUNT.load(&deps.storage).unwrap(), 1);
+        assert!(deposited
+            .may_load(&deps.storage, user_addr)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            COLLATERALTYPES
+                .load(&deps.storage, "om".to_string())
+                .unwrap(),
+            CollateralType {
+                stability_fee: Decimal::zero(),
+                ..om
+            }
+        );
+        assert_eq!(
+            get_contract_version(&deps.storage).unwrap().version,
+            CONTRACT_VERSION
+        );
+
+        // the migrated contract answers like one instantiated at this version
+        let config: ConfigResponse =
+            from_json(&query(deps.as_ref(), env.clone(), QueryMsg::Config {}).unwrap()).unwrap();
+        assert_eq!(config.owner, Addr::unchecked("owner"));
+        assert_eq!(config.stable_token, Some(Addr::unchecked("musd")));
+        assert_eq!(config.total_debt, Uint128::new(1000));
+        assert_eq!(config.accrued_fees, Uint128::zero());
+
+        let now = env.block.time.seconds();
+        deps.querier.update_wasm(move |_| {
+            SystemResult::Ok(ContractResult::Ok(
+                to_json_binary(&FeedPrice {
+                    price: 2_000_000,
+                    timestamp: Some(now),
+                })
+                .unwrap(),
+            ))
+        });
+        let info: InfoResponse =
+            from_json(&query(deps.as_ref(), env.clone(), QueryMsg::Info { vault_id: 1 }).unwrap())
+                .unwrap();
+        assert_eq!(info.collateral, "om");
+        assert_eq!(info.collateral_deposited, Uint128::new(1300));
+        assert_eq!(info.total_debt, Uint128::new(1000));
+
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "9.0.0").unwrap();
+        let err = migrate(deps.as_mut(), env.clone(), MigrateMsg { legacy: None }).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::Downgrade {
+                stored: "9.0.0".to_string(),
+                current: CONTRACT_VERSION.to_string(),
+            }
+            .to_string()
+        );
+
+        set_contract_version(&mut deps.storage, "crates.io:other", "0.1.0").unwrap();
+        let err = migrate(deps.as_mut(), env, MigrateMsg { legacy: None }).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            ContractError::WrongContract {
+                contract: "crates.io:other".to_string(),
+            }
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_lot_price_decay() {
+        let params = om_collateral_type().auction;
+        let lot = Lot {
+            owner: Addr::unchecked("sender"),
+            collateral: "om".to_string(),
+            amount: Uint128::new(1000),
+            debt: Uint128::new(500),
+            tab: Uint128::new(565),
+            start_price: Uint128::new(1_200_000),
+            started_at: 1000,
+        };
+
+        assert_eq!(
+            crate::auction::lot_price(&lot, &params, 1000),
+            Uint128::new(1_200_000)
+        );
+        assert_eq!(
+            crate::auction::lot_price(&lot, &params, 1089),
+            Uint128::new(1_200_000)
+        );
+        assert_eq!(
+            crate::auction::lot_price(&lot, &params, 1090),
+            Uint128::new(1_188_000)
+        );
+        assert_eq!(
+            crate::auction::lot_price(&lot, &params, 1180),
+            Uint128::new(1_176_120)
+        );
+    }
+
+    #[test]
+    fn test_psm_swaps() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+        mint_native(
+            &mut app,
+            user_addr.to_string(),
+            "uusdc".to_string(),
+            10_000u128,
+        );
+
+        let set_msg = ExecuteMsg::SetPsmAsset {
+            asset: "usdc".to_string(),
+            psm_asset: PsmAsset {
+                denom: Denom::Native("uusdc".to_string()),
+                tin: Decimal::percent(1),
+                tout: Decimal::percent(1),
+                debt_ceiling: Uint128::new(5000),
+            },
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap();
+
+        let sell_msg = ExecuteMsg::PsmSell {
+            asset: "usdc".to_string(),
+            amount: Uint128::new(6000),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &sell_msg,
+            &vec![coin(6000, "uusdc")],
+        )
+        .unwrap_err();
+
+        let sell_msg = ExecuteMsg::PsmSell {
+            asset: "usdc".to_string(),
+            amount: Uint128::new(1000),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &sell_msg,
+            &vec![coin(1000, "uusdc")],
+        )
+        .unwrap();
+
+        let stable_bal;
+        (stable_bal, app) = get_cw20_balance(user_addr.clone(), app, contract_addrss.clone());
+        assert_eq!(stable_bal, Uint128::new(990));
+
+        let allow_msg = cw20_base::msg::ExecuteMsg::IncreaseAllowance {
+            spender: stable_engine.clone().into(),
+            amount: Uint128::new(1000000),
+            expires: None,
+        };
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &allow_msg, &[])
+            .unwrap();
+
+        let buy_msg = ExecuteMsg::PsmBuy {
+            asset: "usdc".to_string(),
+            amount: Uint128::new(500),
+        };
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &buy_msg, &[])
+            .unwrap();
+
+        let usdc = app.wrap().query_balance(&user_addr, "uusdc").unwrap();
+        assert_eq!(usdc.amount, Uint128::new(9500));
+
+        let psm_response: PsmAssetResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::PsmAsset {
+                    asset: "usdc".to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(psm_response.reserve, Uint128::new(500));
+        assert_eq!(psm_response.available, Uint128::new(4500));
+
+        let config_response: ConfigResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Config {})
+            .unwrap();
+        assert_eq!(config_response.accrued_fees, Uint128::new(15));
+
+        let balance: SystemBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::SystemBalance {})
+            .unwrap();
+        assert_eq!(balance.surplus, Uint128::new(15));
+        assert_eq!(balance.bad_debt, Uint128::zero());
+        assert!(balance.fully_backed);
+        assert_eq!(
+            balance.supply + balance.surplus + balance.savings,
+            balance.total_debt + balance.auction_debt
+        );
+    }
+
+    #[test]
+    fn test_oracle_price_validation() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(100u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let borrow_msg = ExecuteMsg::BorrowTokens {
+            vault_id: 1,
+            token_amount: Uint128::new(10),
+        };
+
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 5_000_000);
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::PriceDeviation {
+                symbol: "OM".to_string(),
+                price: Uint128::new(5_000_000),
+            }
+            .to_string()
+        );
+
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 0);
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InvalidPrice {
+                symbol: "OM".to_string(),
+            }
+            .to_string()
+        );
+
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 2_100_000);
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap();
+    }
+
+    #[test]
+    fn test_oracle_sources_aggregation() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+        set_oracle_price(&mut app, stable_engine.clone(), "OM2", 2_200_000);
+
+        let config: ConfigResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Config {})
+            .unwrap();
+        let hongbai = |symbol: &str| OracleAdapter::Hongbai {
+            contract: config.oracle.clone(),
+            symbol: symbol.to_string(),
+        };
+        let sources = vec!["OM", "OM2", "OM3"]
+            .into_iter()
+            .map(|symbol| OracleSource {
+                adapter: hongbai(symbol),
+                weight: 1,
+            })
+            .collect::<Vec<_>>();
+
+        let first_config = OracleConfig {
+            sources: sources.clone(),
+            aggregation: Aggregation::Median,
+            quorum: 2,
+            fallback: None,
+        };
+
+        // "om" already has a vault priced by the default oracle
+        let set_msg = ExecuteMsg::SetOracleSources {
+            collateral: "om".to_string(),
+            oracle_config: first_config.clone(),
+        };
+        let err = app
+            .execute_contract(owner_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InvalidOracleConfig {
+                reason: "collateral has vaults, change it through UpdateConfig".to_string(),
+            }
+            .to_string()
+        );
+
+        let collateral_msg = ExecuteMsg::SetCollateralType {
+            collateral: "xom".to_string(),
+            collateral_type: om_collateral_type(),
+        };
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &collateral_msg,
+            &[],
+        )
+        .unwrap();
+        let set_msg = ExecuteMsg::SetOracleSources {
+            collateral: "xom".to_string(),
+            oracle_config: first_config,
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap();
+
+        let prices_msg = QueryMsg::OraclePrices {
+            collateral: "xom".to_string(),
+        };
+        let prices: OraclePricesResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &prices_msg)
+            .unwrap();
+        assert_eq!(prices.aggregate, Some(Uint128::new(2_100_000)));
+        assert_eq!(prices.sources[2].price, None);
+
+        let oracle_config = OracleConfig {
+            sources,
+            aggregation: Aggregation::Median,
+            quorum: 3,
+            fallback: Some(hongbai("OM")),
+        };
+        let set_msg = ExecuteMsg::SetOracleSources {
+            collateral: "xom".to_string(),
+            oracle_config: oracle_config.clone(),
+        };
+        let err = app
+            .execute_contract(owner_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InvalidOracleConfig {
+                reason: "already registered, change it through UpdateConfig".to_string(),
+            }
+            .to_string()
+        );
+
+        // later changes wait for the timelock like any other oracle setting
+        let update_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                collateral: Some("xom".to_string()),
+                oracle_sources: Some(oracle_config),
+                ..ConfigUpdate::default()
+            },
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &update_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(86400));
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 2_000_000);
+        set_oracle_price(&mut app, stable_engine.clone(), "OM2", 2_200_000);
+        let prices: OraclePricesResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &prices_msg)
+            .unwrap();
+        assert_eq!(prices.aggregate, Some(Uint128::new(2_100_000)));
+
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::ApplyUpdate { id: 1 },
+            &[],
+        )
+        .unwrap();
+        let prices: OraclePricesResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &prices_msg)
+            .unwrap();
+        assert_eq!(prices.aggregate, Some(Uint128::new(2_000_000)));
+    }
+
+    #[test]
+    fn test_twap_observations() {
+        let mut storage = cosmwasm_std::testing::MockStorage::new();
+        let mut env = cosmwasm_std::testing::mock_env();
+
+        assert_eq!(twap_price(&storage, &env, "om", 100).unwrap(), None);
+        record_observation(&mut storage, &env, "om", Uint128::new(2_000_000), 100).unwrap();
+        assert_eq!(
+            twap_price(&storage, &env, "om", 100).unwrap(),
+            Some(Uint128::new(2_000_000))
+        );
+
+        // the spike carries no weight until time passes
+        env.block.time = env.block.time.plus_seconds(60);
+        record_observation(&mut storage, &env, "om", Uint128::new(1_000_000), 100).unwrap();
+        assert_eq!(
+            twap_price(&storage, &env, "om", 100).unwrap(),
+            Some(Uint128::new(2_000_000))
+        );
+
+        env.block.time = env.block.time.plus_seconds(20);
+        assert_eq!(
+            twap_price(&storage, &env, "om", 100).unwrap(),
+            Some(Uint128::new(1_750_000))
+        );
+
+        env.block.time = env.block.time.plus_seconds(40);
+        assert_eq!(
+            twap_price(&storage, &env, "om", 100).unwrap(),
+            Some(Uint128::new(1_400_000))
+        );
+
+        env.block.time = env.block.time.plus_seconds(100);
+        record_observation(&mut storage, &env, "om", Uint128::new(1_000_000), 100).unwrap();
+        assert_eq!(
+            twap_price(&storage, &env, "om", 100).unwrap(),
+            Some(Uint128::new(1_000_000))
+        );
+        let kept = OBSERVATIONS
+            .prefix("om".to_string())
+            .keys(&storage, None, None, Order::Ascending)
+            .count();
+        assert_eq!(kept, 2);
+    }
+
+    #[test]
+    fn test_liquidation_uses_twap() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(2000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let liquidate_msg = ExecuteMsg::Liquidate {
+            vault_id: 1,
+            amount_token: Uint128::new(2000),
+        };
+        let poke_msg = ExecuteMsg::Poke {
+            collateral: "om".to_string(),
+        };
+
+        // the spot price makes the position unsafe, the TWAP does not yet
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 1_500_000);
+        let err = app
+            .execute_contract(
+                owner_addr.clone(),
+                stable_engine.clone(),
+                &liquidate_msg,
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::HealthFactorSafe {}.to_string()
+        );
+
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &poke_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &liquidate_msg,
+            &[],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_simulate_liquidation() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(2000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let allow_msg = cw20_base::msg::ExecuteMsg::IncreaseAllowance {
+            spender: stable_engine.clone().into(),
+            amount: Uint128::new(1000000),
+            expires: None,
+        };
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &allow_msg, &[])
+            .unwrap();
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::DepositToPool {
+                amount: Uint128::new(600),
+            },
+            &[],
+        )
+        .unwrap();
+
+        let simulate = |app: &App, vault_id: u64| -> SimulateLiquidationResponse {
+            app.wrap()
+                .query_wasm_smart(
+                    stable_engine.clone(),
+                    &QueryMsg::SimulateLiquidation {
+                        vault_id,
+                        amount_token: Uint128::new(2000),
+                    },
+                )
+                .unwrap()
+        };
+
+        // failures are reported instead of failing the query
+        let simulation = simulate(&app, 99);
+        assert_eq!(
+            simulation.error,
+            Some(ContractError::UnknownVault { vault_id: 99 }.to_string())
+        );
+        assert_eq!(simulation.seized, Uint128::zero());
+
+        let simulation = simulate(&app, 1);
+        assert_eq!(
+            simulation.error,
+            Some(ContractError::HealthFactorSafe {}.to_string())
+        );
+        assert_eq!(simulation.debt, Uint128::zero());
+
+        let poke_msg = ExecuteMsg::Poke {
+            collateral: "om".to_string(),
+        };
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 1_500_000);
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &poke_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+
+        // the pool covers what it holds, a lot takes the rest with the penalty
+        let simulation = simulate(&app, 1);
+        assert_eq!(simulation.error, None);
+        assert_eq!(simulation.debt, Uint128::new(2000));
+        assert_eq!(simulation.pool_debt, Uint128::new(600));
+        assert!(simulation.seized > simulation.pool_collateral);
+        assert_eq!(simulation.bonus, Uint128::new(1400) * Decimal::percent(13));
+
+        let liquidate_msg = ExecuteMsg::Liquidate {
+            vault_id: 1,
+            amount_token: Uint128::new(2000),
+        };
+        let response = app
+            .execute_contract(
+                owner_addr.clone(),
+                stable_engine.clone(),
+                &liquidate_msg,
+                &[],
+            )
+            .unwrap();
+        let attribute = |key: &str| {
+            response
+                .events
+                .iter()
+                .flat_map(|event| event.attributes.iter())
+                .find(|attribute| attribute.key == key)
+                .unwrap()
+                .value
+                .clone()
+        };
+        assert_eq!(attribute("seized"), simulation.seized.to_string());
+        assert_eq!(attribute("debt"), simulation.debt.to_string());
+        assert_eq!(attribute("pool_debt"), simulation.pool_debt.to_string());
+        assert_eq!(
+            attribute("pool_collateral"),
+            simulation.pool_collateral.to_string()
+        );
+        assert_eq!(
+            attribute("tab"),
+            (simulation.debt - simulation.pool_debt + simulation.bonus).to_string()
+        );
+    }
+
+    #[test]
+    fn test_position_queries() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(2000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+        open_vault(&mut app, stable_engine.clone(), owner_addr.clone(), "om");
+
+        let page: PositionsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::AllPositions {
+                    start_after: None,
+                    limit: Some(1),
+                },
+            )
+            .unwrap();
+        assert_eq!(page.positions.len(), 1);
+        assert_eq!(page.positions[0].vault_id, 1);
+        assert_eq!(page.positions[0].owner, user_addr);
+        let page: PositionsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::AllPositions {
+                    start_after: Some(1),
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(page.positions.len(), 1);
+        assert_eq!(page.positions[0].vault_id, 2);
+
+        let owned: PositionsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::PositionsByOwner {
+                    owner: owner_addr.to_string(),
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert_eq!(owned.positions.len(), 1);
+        assert_eq!(owned.positions[0].vault_id, 2);
+        assert_eq!(owned.positions[0].owner, owner_addr);
+
+        // borrowing and withdrawing up to the reported limits keeps the vault safe
+        let max_borrow: MaxBorrowResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::MaxBorrow { vault_id: 1 })
+            .unwrap();
+        assert!(!max_borrow.amount.is_zero());
+        let borrow_msg = ExecuteMsg::BorrowTokens {
+            vault_id: 1,
+            token_amount: max_borrow.amount,
+        };
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap();
+        let max_borrow: MaxBorrowResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::MaxBorrow { vault_id: 1 })
+            .unwrap();
+        assert_eq!(max_borrow.amount, Uint128::zero());
+
+        let max_withdraw: MaxWithdrawResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::MaxWithdraw { vault_id: 1 },
+            )
+            .unwrap();
+        let redeem_msg = ExecuteMsg::RedeemCollateral {
+            vault_id: 1,
+            amount: max_withdraw.amount + Uint128::new(1),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &redeem_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::HealthFactorLess {}.to_string()
+        );
+
+        let unsafe_msg = QueryMsg::UnsafePositions { limit: None };
+        let unsafe_positions: PositionsResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &unsafe_msg)
+            .unwrap();
+        assert!(unsafe_positions.positions.is_empty());
+
+        // once the TWAP catches up with the lower price the vault shows up
+        let poke_msg = ExecuteMsg::Poke {
+            collateral: "om".to_string(),
+        };
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 1_500_000);
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &poke_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+        let unsafe_positions: PositionsResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &unsafe_msg)
+            .unwrap();
+        assert_eq!(unsafe_positions.positions.len(), 1);
+        assert_eq!(unsafe_positions.positions[0].vault_id, 1);
+
+        // a collateral type whose price cannot be looked up is skipped
+        let set_msg = ExecuteMsg::SetCollateralType {
+            collateral: "atom".to_string(),
+            collateral_type: CollateralType {
+                denom: Denom::Native("uatom".to_string()),
+                oracle_symbol: "ATOM".to_string(),
+                ..om_collateral_type()
+            },
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap();
+        set_oracle_price(&mut app, stable_engine.clone(), "ATOM", 2_000_000);
+        mint_native(&mut app, user_addr.to_string(), "uatom".to_string(), 1300);
+        let vault_id = open_vault(&mut app, stable_engine.clone(), user_addr.clone(), "atom");
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id,
+            token_amount: Uint128::new(1000),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uatom")],
+        )
+        .unwrap();
+        set_oracle_price(&mut app, stable_engine.clone(), "ATOM", 0);
+        let unsafe_positions: PositionsResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &unsafe_msg)
+            .unwrap();
+        assert_eq!(unsafe_positions.positions.len(), 1);
+        assert_eq!(unsafe_positions.positions[0].vault_id, 1);
+    }
+
+    #[test]
+    fn test_redemption() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(1000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+        let vault_id = open_vault(&mut app, stable_engine.clone(), user_addr.clone(), "om");
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id,
+            token_amount: Uint128::from(1000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(2000, "uom")],
+        )
+        .unwrap();
+
+        let allow_msg = cw20_base::msg::ExecuteMsg::IncreaseAllowance {
+            spender: stable_engine.clone().into(),
+            amount: Uint128::new(1000000),
+            expires: None,
+        };
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &allow_msg, &[])
+            .unwrap();
+
+        // redeeming a quarter of the supply lifts the base rate to 12.5%
+        let redeem_msg = ExecuteMsg::Redeem {
+            collateral: "om".to_string(),
+            amount: Uint128::new(500),
+            max_fee_rate: Decimal::percent(5),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &redeem_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::RedemptionFeeTooHigh {
+                fee_rate: Decimal::percent(13)
+            }
+            .to_string()
+        );
+
+        let before = app.wrap().query_balance(&user_addr, "uom").unwrap();
+        let redeem_msg = ExecuteMsg::Redeem {
+            collateral: "om".to_string(),
+            amount: Uint128::new(500),
+            max_fee_rate: Decimal::percent(20),
+        };
+        let response = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &redeem_msg, &[])
+            .unwrap();
+        let redemptions = response
+            .events
+            .iter()
+            .filter(|event| event.ty == "wasm-redemption")
+            .collect::<Vec<_>>();
+        assert_eq!(redemptions.len(), 1);
+        let attribute = |key: &str| {
+            redemptions[0]
+                .attributes
+                .iter()
+                .find(|attribute| attribute.key == key)
+                .unwrap()
+                .value
+                .clone()
+        };
+        assert_eq!(attribute("vault_id"), "1");
+        assert_eq!(attribute("debt"), "500");
+        assert_eq!(attribute("collateral"), "250");
+
+        // the lowest ratio vault pays, the other one is untouched
+        let info: InfoResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Info { vault_id: 1 })
+            .unwrap();
+        assert_eq!(info.total_debt, Uint128::new(500));
+        assert_eq!(info.collateral_deposited, Uint128::new(1050));
+        let info: InfoResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Info { vault_id })
+            .unwrap();
+        assert_eq!(info.total_debt, Uint128::new(1000));
+        assert_eq!(info.collateral_deposited, Uint128::new(2000));
+
+        // 13% of the 250 collateral drawn goes to the treasury
+        let after = app.wrap().query_balance(&user_addr, "uom").unwrap();
+        assert_eq!(after.amount - before.amount, Uint128::new(218));
+        let treasury = app
+            .wrap()
+            .query_balance(Addr::unchecked("treasury"), "uom")
+            .unwrap();
+        assert_eq!(treasury.amount, Uint128::new(32));
+
+        // the base rate halves every 12 hours
+        app.update_block(|block| block.time = block.time.plus_seconds(43200));
+        let redemption_rate: RedemptionRateResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::RedemptionRate {})
+            .unwrap();
+        assert!(redemption_rate.base_rate > Decimal::permille(62));
+        assert!(redemption_rate.base_rate < Decimal::permille(63));
+        assert_eq!(
+            redemption_rate.fee_rate,
+            redemption_rate.base_rate + Decimal::permille(5)
+        );
+    }
+
+    #[test]
+    fn test_stability_pool() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(2000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let allow_msg = cw20_base::msg::ExecuteMsg::IncreaseAllowance {
+            spender: stable_engine.clone().into(),
+            amount: Uint128::new(1000000),
+            expires: None,
+        };
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &allow_msg, &[])
+            .unwrap();
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::DepositToPool {
+                amount: Uint128::new(600),
+            },
+            &[],
+        )
+        .unwrap();
+        let stable_bal;
+        (stable_bal, app) = get_cw20_balance(user_addr.clone(), app, contract_addrss.clone());
+        assert_eq!(stable_bal, Uint128::new(1400));
+
+        let poke_msg = ExecuteMsg::Poke {
+            collateral: "om".to_string(),
+        };
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 1_500_000);
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &poke_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+
+        // the pool absorbs the debt it can cover, at a premium of the penalty
+        let simulation: SimulateLiquidationResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::SimulateLiquidation {
+                    vault_id: 1,
+                    amount_token: Uint128::new(300),
+                },
+            )
+            .unwrap();
+        assert_eq!(simulation.error, None);
+        assert_eq!(simulation.pool_debt, Uint128::new(300));
+        assert_eq!(simulation.seized, simulation.pool_collateral);
+
+        let liquidate_msg = ExecuteMsg::Liquidate {
+            vault_id: 1,
+            amount_token: Uint128::new(300),
+        };
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &liquidate_msg,
+            &[],
+        )
+        .unwrap();
+        let lots: LotsResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::Lots {
+                    start_after: None,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        assert!(lots.lots.is_empty());
+
+        let pool: StabilityPoolResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::StabilityPool {})
+            .unwrap();
+        assert_eq!(pool.total, Uint128::new(300));
+        assert_eq!(
+            pool.collateral,
+            vec![CollateralGainResponse {
+                collateral: "om".to_string(),
+                amount: simulation.pool_collateral,
+            }]
+        );
+
+        // the loss is rounded up, the gain down
+        let deposit: PoolDepositResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::PoolDeposit {
+                    depositor: user_addr.to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(deposit.deposit, Uint128::new(299));
+        assert_eq!(deposit.gains.len(), 1);
+        let gain = deposit.gains[0].amount;
+        assert!(gain <= simulation.pool_collateral);
+        assert!(gain + Uint128::new(1) >= simulation.pool_collateral);
+
+        let before = app.wrap().query_balance(&user_addr, "uom").unwrap();
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::ClaimCollateralGains {},
+            &[],
+        )
+        .unwrap();
+        let after = app.wrap().query_balance(&user_addr, "uom").unwrap();
+        assert_eq!(after.amount - before.amount, gain);
+
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::WithdrawFromPool {
+                amount: Uint128::new(1000),
+            },
+            &[],
+        )
+        .unwrap();
+        let stable_bal;
+        (stable_bal, app) = get_cw20_balance(user_addr.clone(), app, contract_addrss.clone());
+        assert_eq!(stable_bal, Uint128::new(1699));
+        let deposit: PoolDepositResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::PoolDeposit {
+                    depositor: user_addr.to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(deposit.deposit, Uint128::zero());
+        assert!(deposit.gains.is_empty());
+    }
+
+    #[test]
+    fn test_savings_rate() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(2000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let update_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                savings_rate: Some(Decimal::percent(1)),
+                ..ConfigUpdate::default()
+            },
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &update_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(86400));
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::ApplyUpdate { id: 1 },
+            &[],
+        )
+        .unwrap();
+
+        let allow_msg = cw20_base::msg::ExecuteMsg::IncreaseAllowance {
+            spender: stable_engine.clone().into(),
+            amount: Uint128::new(1000000),
+            expires: None,
+        };
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &allow_msg, &[])
+            .unwrap();
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::Join {
+                amount: Uint128::new(1000),
+            },
+            &[],
+        )
+        .unwrap();
+        let stable_bal;
+        (stable_bal, app) = get_cw20_balance(user_addr.clone(), app, contract_addrss.clone());
+        assert_eq!(stable_bal, Uint128::new(1000));
+
+        app.update_block(|block| {
+            block.time = block.time.plus_seconds(crate::rates::SECONDS_PER_YEAR)
+        });
+        let savings_query = QueryMsg::SavingsBalance {
+            address: user_addr.to_string(),
+        };
+        let savings: SavingsBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &savings_query)
+            .unwrap();
+        assert_eq!(savings.balance, Uint128::new(1010));
+        assert_eq!(savings.chi, Decimal::percent(101));
+
+        // the interest comes out of the stability fees
+        let balance: SystemBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::SystemBalance {})
+            .unwrap();
+        assert_eq!(balance.savings, Uint128::new(1010));
+        assert_eq!(balance.bad_debt, Uint128::zero());
+        assert_eq!(
+            balance.supply + balance.surplus + balance.savings,
+            balance.total_debt + balance.auction_debt
+        );
+
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::Exit {
+                amount: Uint128::new(5000),
+            },
+            &[],
+        )
+        .unwrap();
+        let stable_bal;
+        (stable_bal, app) = get_cw20_balance(user_addr.clone(), app, contract_addrss.clone());
+        assert_eq!(stable_bal, Uint128::new(2010));
+        let savings: SavingsBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &savings_query)
+            .unwrap();
+        assert_eq!(savings.balance, Uint128::zero());
+    }
+
+    #[test]
+    fn test_savings_rate_above_fees() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(2000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let update_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                savings_rate: Some(Decimal::one()),
+                ..ConfigUpdate::default()
+            },
+        };
+        let err = app
+            .execute_contract(owner_addr.clone(), stable_engine.clone(), &update_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InvalidConfigUpdate {
+                reason: "savings_rate must be below 1".to_string(),
+            }
+            .to_string()
+        );
+
+        // 10% on savings while vaults pay a 2% stability fee
+        let update_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                savings_rate: Some(Decimal::percent(10)),
+                ..ConfigUpdate::default()
+            },
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &update_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(86400));
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::ApplyUpdate { id: 1 },
+            &[],
+        )
+        .unwrap();
+
+        let allow_msg = cw20_base::msg::ExecuteMsg::IncreaseAllowance {
+            spender: stable_engine.clone().into(),
+            amount: Uint128::new(1000000),
+            expires: None,
+        };
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &allow_msg, &[])
+            .unwrap();
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::Join {
+                amount: Uint128::new(1000),
+            },
+            &[],
+        )
+        .unwrap();
+        app.update_block(|block| {
+            block.time = block.time.plus_seconds(crate::rates::SECONDS_PER_YEAR)
+        });
+
+        // the interest the fees cannot pay for is booked as bad debt
+        let balance: SystemBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::SystemBalance {})
+            .unwrap();
+        assert_eq!(balance.savings, Uint128::new(1100));
+        assert_eq!(balance.surplus, Uint128::zero());
+        assert!(!balance.bad_debt.is_zero());
+        assert!(!balance.fully_backed);
+        assert_eq!(
+            balance.supply + balance.surplus + balance.savings,
+            balance.total_debt + balance.auction_debt + balance.bad_debt
+        );
+    }
+
+    #[test]
+    fn test_flash_mint() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(2000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let borrower_id = app.store_code(mock_borrower());
+        let borrower = app
+            .instantiate_contract(
+                borrower_id,
+                owner_addr.clone(),
+                &Empty {},
+                &[],
+                "Borrower",
+                None,
+            )
+            .unwrap();
+        // the borrower brings the fee
+        let transfer_msg = cw20_base::msg::ExecuteMsg::Transfer {
+            recipient: borrower.to_string(),
+            amount: Uint128::new(10),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            contract_addrss.clone(),
+            &transfer_msg,
+            &[],
+        )
+        .unwrap();
+
+        let borrow = |amount: u128, repay: bool| MockBorrowerExecuteMsg::Borrow {
+            engine: stable_engine.to_string(),
+            token: contract_addrss.to_string(),
+            amount: Uint128::new(amount),
+            repay,
+        };
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                borrower.clone(),
+                &borrow(1000, false),
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            ContractError::FlashMintNotRepaid {
+                owed: Uint128::new(1010)
+            }
+            .to_string()
+        );
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                borrower.clone(),
+                &borrow(1_000_001, true),
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            ContractError::FlashMintTooLarge {
+                max: Uint128::new(1_000_000)
+            }
+            .to_string()
+        );
+
+        app.execute_contract(
+            user_addr.clone(),
+            borrower.clone(),
+            &borrow(1000, true),
+            &[],
+        )
+        .unwrap();
+        let borrower_bal;
+        (borrower_bal, app) = get_cw20_balance(borrower.clone(), app, contract_addrss.clone());
+        assert_eq!(borrower_bal, Uint128::zero());
+
+        // the fee is burned and booked as surplus
+        let balance: SystemBalanceResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::SystemBalance {})
+            .unwrap();
+        assert_eq!(balance.supply, Uint128::new(1990));
+        assert_eq!(
+            balance.supply + balance.surplus + balance.savings,
+            balance.total_debt + balance.auction_debt
+        );
+    }
+
+    #[test]
+    fn test_cw20_collateral_receive() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let cw20_id = app.store_code(cw20_stable());
+        let collateral_token = app
+            .instantiate_contract(
+                cw20_id,
+                owner_addr.clone(),
+                &cw20_base::msg::InstantiateMsg {
+                    name: "Wrapped OM".into(),
+                    symbol: "wOM".into(),
+                    decimals: 6,
+                    initial_balances: vec![cw20::Cw20Coin {
+                        address: user_addr.to_string(),
+                        amount: Uint128::new(5000),
+                    }],
+                    mint: None,
+                    marketing: None,
+                },
+                &[],
+                "Collateral_token",
+                None,
+            )
+            .unwrap();
+
+        let set_msg = ExecuteMsg::SetCollateralType {
+            collateral: "wom".to_string(),
+            collateral_type: CollateralType {
+                denom: Denom::Cw20(collateral_token.clone()),
+                ..om_collateral_type()
+            },
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap();
+        open_vault(&mut app, stable_engine.clone(), user_addr.clone(), "wom");
+
+        // native funds cannot stand in for a cw20 collateral
+        let native_msg = ExecuteMsg::DepositCollateral { vault_id: 2 };
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                stable_engine.clone(),
+                &native_msg,
+                &vec![coin(1300, "uom")],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InvalidCollateralAsset {
+                collateral: "wom".to_string(),
+            }
+            .to_string()
+        );
+
+        let send_msg = Cw20ExecuteMsg::Send {
+            contract: stable_engine.to_string(),
+            amount: Uint128::new(1300),
+            msg: to_json_binary(&ReceiveMsg::DepositAndMint {
+                vault_id: 2,
+                token_amount: Uint128::new(1000),
+            })
+            .unwrap(),
+        };
+        app.execute_contract(user_addr.clone(), collateral_token.clone(), &send_msg, &[])
+            .unwrap();
+
+        let info_msg = QueryMsg::Info { vault_id: 2 };
+        let info: InfoResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &info_msg)
+            .unwrap();
+        assert_eq!(info.collateral_deposited, Uint128::new(1300));
+        assert_eq!(info.total_debt, Uint128::new(1000));
+
+        // mUSD sent back repays the debt without an allowance, the rest is refunded
+        let repay_msg = Cw20ExecuteMsg::Send {
+            contract: stable_engine.to_string(),
+            amount: Uint128::new(600),
+            msg: to_json_binary(&ReceiveMsg::Repay { vault_id: 2 }).unwrap(),
+        };
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &repay_msg, &[])
+            .unwrap();
+        let info: InfoResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &info_msg)
+            .unwrap();
+        assert_eq!(info.total_debt, Uint128::new(400));
+
+        let repay_msg = Cw20ExecuteMsg::Send {
+            contract: stable_engine.to_string(),
+            amount: Uint128::new(400),
+            msg: to_json_binary(&ReceiveMsg::Repay { vault_id: 2 }).unwrap(),
+        };
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &repay_msg, &[])
+            .unwrap();
+        let (balance, app) = get_cw20_balance(user_addr.clone(), app, contract_addrss.clone());
+        assert_eq!(balance, Uint128::zero());
+
+        // collateral tokens cannot be used to repay
+        let mut app = app;
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                collateral_token.clone(),
+                &Cw20ExecuteMsg::Send {
+                    contract: stable_engine.to_string(),
+                    amount: Uint128::new(10),
+                    msg: to_json_binary(&ReceiveMsg::Repay { vault_id: 2 }).unwrap(),
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            ContractError::UnexpectedToken {
+                token: collateral_token.to_string(),
+            }
+            .to_string()
+        );
+    }
+
+    #[test]
+    fn test_debt_ceilings() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let update_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                collateral: Some("om".to_string()),
+                debt_ceiling: Some(Uint128::new(1500)),
+                ..ConfigUpdate::default()
+            },
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &update_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(86400));
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::ApplyUpdate { id: 1 },
+            &[],
+        )
+        .unwrap();
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 2_000_000);
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(1000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let borrow_msg = ExecuteMsg::BorrowTokens {
+            vault_id: 1,
+            token_amount: Uint128::new(600),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::DebtCeilingExceeded {
+                scope: "om".to_string(),
+            }
+            .to_string()
+        );
+
+        let ceiling_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                global_debt_ceiling: Some(Uint128::new(1200)),
+                ..ConfigUpdate::default()
+            },
+        };
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &ceiling_msg, &[])
+            .unwrap_err();
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &ceiling_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(86400));
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::ApplyUpdate { id: 1 },
+            &[],
+        )
+        .unwrap();
+
+        let borrow_msg = ExecuteMsg::BorrowTokens {
+            vault_id: 1,
+            token_amount: Uint128::new(300),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::DebtCeilingExceeded {
+                scope: "global".to_string(),
+            }
+            .to_string()
+        );
+
+        let config: ConfigResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Config {})
+            .unwrap();
+        assert_eq!(config.debt_ceiling, Uint128::new(1200));
+        assert_eq!(config.total_debt, Uint128::new(1000));
+    }
+
+    #[test]
+    fn test_timelocked_updates() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        // registered types only change through the timelock
+        let set_msg = ExecuteMsg::SetCollateralType {
+            collateral: "om".to_string(),
+            collateral_type: om_collateral_type(),
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &set_msg, &[])
+            .unwrap_err();
+
+        let update_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                collateral: Some("om".to_string()),
+                liquidity_threashold: Some(Uint128::new(150)),
+                ..ConfigUpdate::default()
+            },
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &update_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::MissingRole {
+                role: Role::RiskManager,
+            }
+            .to_string()
+        );
+        let err = app
+            .execute_contract(
+                owner_addr.clone(),
+                stable_engine.clone(),
+                &ExecuteMsg::UpdateConfig {
+                    update: ConfigUpdate {
+                        liquidity_threashold: Some(Uint128::new(150)),
+                        ..ConfigUpdate::default()
+                    },
+                },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::InvalidConfigUpdate {
+                reason: "collateral parameters need a collateral".to_string(),
+            }
+            .to_string()
+        );
+
+        // the resulting collateral type is checked like a new one
+        let auction = om_collateral_type().auction;
+        let invalid_updates = vec![
+            (
+                ConfigUpdate {
+                    auction: Some(AuctionParams {
+                        cut: Decimal::percent(101),
+                        ..auction.clone()
+                    }),
+                    ..ConfigUpdate::default()
+                },
+                "auction cut must be positive and at most 1",
+            ),
+            (
+                ConfigUpdate {
+                    auction: Some(AuctionParams { step: 0, ..auction }),
+                    ..ConfigUpdate::default()
+                },
+                "auction step and duration must be positive",
+            ),
+            (
+                ConfigUpdate {
+                    min_threashold: Some(Uint128::zero()),
+                    ..ConfigUpdate::default()
+                },
+                "min_threashold must be positive",
+            ),
+            (
+                ConfigUpdate {
+                    stability_fee: Some(Decimal::one()),
+                    ..ConfigUpdate::default()
+                },
+                "stability_fee must be below 1",
+            ),
+        ];
+        for (update, reason) in invalid_updates {
+            let msg = ExecuteMsg::UpdateConfig {
+                update: ConfigUpdate {
+                    collateral: Some("om".to_string()),
+                    ..update
+                },
+            };
+            let err = app
+                .execute_contract(owner_addr.clone(), stable_engine.clone(), &msg, &[])
+                .unwrap_err();
+            assert_eq!(
+                err.downcast::<ContractError>().unwrap().to_string(),
+                ContractError::InvalidCollateralType {
+                    reason: reason.to_string(),
+                }
+                .to_string()
+            );
+        }
+
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &update_msg, &[])
+            .unwrap();
+        let oracle_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                oracle: Some("new_oracle".to_string()),
+                ..ConfigUpdate::default()
+            },
+        };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &oracle_msg, &[])
+            .unwrap();
+
+        let pending: PendingUpdatesResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::PendingUpdates {})
+            .unwrap();
+        assert_eq!(pending.updates.len(), 2);
+        assert_eq!(
+            pending.updates[0].effective_at,
+            app.block_info().time.seconds() + 86400
+        );
+
+        let apply_msg = ExecuteMsg::ApplyUpdate { id: 1 };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &apply_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::UpdateNotReady {
+                effective_at: pending.updates[0].effective_at,
+            }
+            .to_string()
+        );
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::CancelUpdate { id: 2 },
+            &[],
+        )
+        .unwrap();
+
+        app.update_block(|block| block.time = block.time.plus_seconds(86400));
+        let err = app
+            .execute_contract(
+                owner_addr.clone(),
+                stable_engine.clone(),
+                &ExecuteMsg::CancelUpdate { id: 1 },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::UpdateEffective {
+                effective_at: pending.updates[0].effective_at,
+            }
+            .to_string()
+        );
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &apply_msg, &[])
+            .unwrap();
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &apply_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::UnknownUpdate { id: 1 }.to_string()
+        );
+
+        let collateral_type: CollateralTypeResponse = app
+            .wrap()
+            .query_wasm_smart(
+                stable_engine.clone(),
+                &QueryMsg::CollateralType {
+                    collateral: "om".to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            collateral_type.collateral_type.liquidity_threashold,
+            Uint128::new(150)
+        );
+        let config: ConfigResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Config {})
+            .unwrap();
+        assert_ne!(config.oracle, Addr::unchecked("new_oracle"));
+        let pending: PendingUpdatesResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::PendingUpdates {})
+            .unwrap();
+        assert!(pending.updates.is_empty());
+    }
+
+    #[test]
+    fn test_global_settlement() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(1000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::Shutdown {},
+            &[],
+        )
+        .unwrap_err();
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::Shutdown {},
+            &[],
+        )
+        .unwrap();
+
+        let borrow_msg = ExecuteMsg::BorrowTokens {
+            vault_id: 1,
+            token_amount: Uint128::new(10),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::SystemShutdown {}.to_string()
+        );
+
+        // anyone can settle; 1000 mUSD of debt at $2 takes 500 uom
+        let settle_msg = ExecuteMsg::SettleVault { vault_id: 1 };
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &settle_msg, &[])
+            .unwrap();
+        let withdraw_msg = ExecuteMsg::WithdrawExcessCollateral { vault_id: 1 };
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &withdraw_msg, &[])
+            .unwrap();
+        let uom = app.wrap().query_balance(&user_addr, "uom").unwrap();
+        assert_eq!(uom.amount, Uint128::new(1_100_000 - 500));
+
+        let redeem_msg = Cw20ExecuteMsg::Send {
+            contract: stable_engine.to_string(),
+            amount: Uint128::new(1000),
+            msg: to_json_binary(&ReceiveMsg::RedeemSettled {}).unwrap(),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), contract_addrss.clone(), &redeem_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            ContractError::RatesNotFixed {}.to_string()
+        );
+
+        // the redemption rate is fixed once the delay has passed
+        let fix_msg = ExecuteMsg::FixRates {};
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &fix_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::SettlementNotReady {
+                redeemable_at: app.block_info().time.seconds() + 3600,
+            }
+            .to_string()
+        );
+        app.update_block(|block| block.time = block.time.plus_seconds(3600));
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &fix_msg, &[])
+            .unwrap();
+        let settlement: SettlementResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Settlement {})
+            .unwrap();
+        assert_eq!(
+            settlement.shutdown.unwrap().unredeemed,
+            Some(Uint128::new(1000))
+        );
+
+        app.execute_contract(user_addr.clone(), contract_addrss.clone(), &redeem_msg, &[])
+            .unwrap();
+        let uom = app.wrap().query_balance(&user_addr, "uom").unwrap();
+        assert_eq!(uom.amount, Uint128::new(1_100_000));
+
+        let settlement: SettlementResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Settlement {})
+            .unwrap();
+        assert_eq!(settlement.collateral[0].price, Uint128::new(2_000_000));
+        assert_eq!(settlement.collateral[0].pool, Uint128::zero());
+    }
+
+    #[test]
+    fn test_settle_lot() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(2000u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let poke_msg = ExecuteMsg::Poke {
+            collateral: "om".to_string(),
+        };
+        set_oracle_price(&mut app, stable_engine.clone(), "OM", 1_500_000);
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &poke_msg, &[])
+            .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(1800));
+
+        let liquidate_msg = ExecuteMsg::Liquidate {
+            vault_id: 1,
+            amount_token: Uint128::new(300),
+        };
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &liquidate_msg,
+            &[],
+        )
+        .unwrap();
+        let lots_msg = QueryMsg::Lots {
+            start_after: None,
+            limit: None,
+        };
+        let lots: LotsResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &lots_msg)
+            .unwrap();
+        let lot = lots.lots[0].lot.clone();
+        assert_eq!(lot.debt, Uint128::new(300));
+
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::Shutdown {},
+            &[],
+        )
+        .unwrap();
+
+        // auctions stop at shutdown
+        let take_msg = ExecuteMsg::TakeLot {
+            lot_id: 1,
+            amount: lot.amount,
+            max_price: Uint128::new(10_000_000),
+        };
+        let err = app
+            .execute_contract(owner_addr.clone(), stable_engine.clone(), &take_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::SystemShutdown {}.to_string()
+        );
+
+        // the vault and the lot must be settled before the rate can be fixed
+        app.update_block(|block| block.time = block.time.plus_seconds(3600));
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                stable_engine.clone(),
+                &ExecuteMsg::FixRates {},
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::UnsettledDebt {}.to_string()
+        );
+
+        // 300 mUSD of debt at the frozen $1.5 takes 200 uom, or all there is
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::SettleLot { lot_id: 1 },
+            &[],
+        )
+        .unwrap();
+        let lots: LotsResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &lots_msg)
+            .unwrap();
+        assert!(lots.lots.is_empty());
+
+        let settlement: SettlementResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Settlement {})
+            .unwrap();
+        assert_eq!(settlement.collateral[0].price, Uint128::new(1_500_000));
+        assert_eq!(
+            settlement.collateral[0].pool,
+            lot.amount.min(Uint128::new(200))
+        );
+
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                stable_engine.clone(),
+                &ExecuteMsg::SettleLot { lot_id: 1 },
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::UnknownLot { lot_id: 1 }.to_string()
+        );
+    }
+
+    #[test]
+    fn test_pause_switches() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+        let guardian_addr = Addr::unchecked("guardian");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let guardian_msg = ExecuteMsg::GrantRole {
+            role: Role::Guardian,
+            address: guardian_addr.to_string(),
+        };
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &guardian_msg,
+            &[],
+        )
+        .unwrap();
+
+        let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
+            token_amount: Uint128::from(100u128),
+        };
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &dep_msg,
+            &vec![coin(1300, "uom")],
+        )
+        .unwrap();
+
+        let pause_msg = ExecuteMsg::Pause {
+            collateral: Some("om".to_string()),
+            ops: vec![PauseOp::Borrow],
+        };
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &pause_msg, &[])
+            .unwrap_err();
+        app.execute_contract(
+            guardian_addr.clone(),
+            stable_engine.clone(),
+            &pause_msg,
+            &[],
+        )
+        .unwrap();
+
+        let borrow_msg = ExecuteMsg::BorrowTokens {
+            vault_id: 1,
+            token_amount: Uint128::new(10),
+        };
+        let err = app
+            .execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::Paused {
+                op: PauseOp::Borrow,
+            }
+            .to_string()
+        );
+
+        // depositing stays open while borrowing is paused
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::DepositCollateral { vault_id: 1 },
+            &vec![coin(100, "uom")],
+        )
+        .unwrap();
+
+        let unpause_msg = ExecuteMsg::Unpause {
+            collateral: Some("om".to_string()),
+            ops: vec![PauseOp::Borrow],
+        };
+        app.execute_contract(
+            guardian_addr.clone(),
+            stable_engine.clone(),
+            &unpause_msg,
+            &[],
+        )
+        .unwrap_err();
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &unpause_msg, &[])
+            .unwrap();
+        app.execute_contract(user_addr.clone(), stable_engine.clone(), &borrow_msg, &[])
+            .unwrap();
+
+        let pause_msg = ExecuteMsg::Pause {
+            collateral: None,
+            ops: vec![PauseOp::Mint, PauseOp::Swap],
+        };
+        app.execute_contract(
+            guardian_addr.clone(),
+            stable_engine.clone(),
+            &pause_msg,
+            &[],
+        )
+        .unwrap();
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                stable_engine.clone(),
+                &dep_msg,
+                &vec![coin(1300, "uom")],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::Paused { op: PauseOp::Mint }.to_string()
+        );
+
+        let pause_state: PauseStateResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::PauseState {})
+            .unwrap();
+        assert_eq!(pause_state.global, vec![PauseOp::Mint, PauseOp::Swap]);
+        assert!(pause_state.collateral.is_empty());
+    }
+
+    #[test]
+    fn test_roles_and_ownership() {
+        let oldapp = App::default();
+
+        let user_addr = Addr::unchecked("sender");
+        let owner_addr = Addr::unchecked("owner");
+        let admin_addr = Addr::unchecked("admin");
+        let risk_addr = Addr::unchecked("risk");
+
+        let (mut app, stable_engine, _contract_addrss) =
+            deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
+
+        let grant = |role: Role, address: &Addr| ExecuteMsg::GrantRole {
+            role,
+            address: address.to_string(),
+        };
+        let ceiling_msg = ExecuteMsg::UpdateConfig {
+            update: ConfigUpdate {
+                global_debt_ceiling: Some(Uint128::new(1_000_000)),
+                ..ConfigUpdate::default()
+            },
+        };
+
+        // only the owner hands out the admin role
+        let err = app
+            .execute_contract(
+                user_addr.clone(),
+                stable_engine.clone(),
+                &grant(Role::Admin, &user_addr),
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::NOTOWNER {}.to_string()
+        );
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &grant(Role::Admin, &admin_addr),
+            &[],
+        )
+        .unwrap();
+        app.execute_contract(
+            admin_addr.clone(),
+            stable_engine.clone(),
+            &grant(Role::RiskManager, &risk_addr),
+            &[],
+        )
+        .unwrap();
+
+        app.execute_contract(risk_addr.clone(), stable_engine.clone(), &ceiling_msg, &[])
+            .unwrap();
+        let err = app
+            .execute_contract(
+                risk_addr.clone(),
+                stable_engine.clone(),
+                &grant(Role::Guardian, &user_addr),
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::MissingRole { role: Role::Admin }.to_string()
+        );
+
+        let revoke_msg = ExecuteMsg::RevokeRole {
+            role: Role::RiskManager,
+            address: risk_addr.to_string(),
+        };
+        app.execute_contract(admin_addr.clone(), stable_engine.clone(), &revoke_msg, &[])
+            .unwrap();
+        app.execute_contract(risk_addr.clone(), stable_engine.clone(), &ceiling_msg, &[])
+            .unwrap_err();
+
+        // ownership moves only once the new owner accepts it
+        let transfer_msg = ExecuteMsg::TransferOwnership {
+            new_owner: user_addr.to_string(),
+        };
+        app.execute_contract(
+            admin_addr.clone(),
+            stable_engine.clone(),
+            &transfer_msg,
+            &[],
+        )
+        .unwrap_err();
+        app.execute_contract(
+            owner_addr.clone(),
+            stable_engine.clone(),
+            &transfer_msg,
+            &[],
+        )
+        .unwrap();
+        let err = app
+            .execute_contract(
+                admin_addr.clone(),
+                stable_engine.clone(),
+                &ExecuteMsg::AcceptOwnership {},
+                &[],
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.downcast::<ContractError>().unwrap().to_string(),
+            ContractError::NotPendingOwner {}.to_string()
+        );
+
+        let roles: RolesResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Roles {})
+            .unwrap();
+        assert_eq!(roles.owner, owner_addr);
+        assert_eq!(roles.pending_owner, Some(user_addr.clone()));
+        assert_eq!(roles.roles[0].members, vec![admin_addr.clone()]);
+        assert!(roles.roles[1].members.is_empty());
+
+        app.execute_contract(
+            user_addr.clone(),
+            stable_engine.clone(),
+            &ExecuteMsg::AcceptOwnership {},
+            &[],
+        )
+        .unwrap();
+        app.execute_contract(owner_addr.clone(), stable_engine.clone(), &ceiling_msg, &[])
+            .unwrap_err();
+        let roles: RolesResponse = app
+            .wrap()
+            .query_wasm_smart(stable_engine.clone(), &QueryMsg::Roles {})
+            .unwrap();
+        assert_eq!(roles.owner, user_addr);
+        assert_eq!(roles.pending_owner, None);
+    }
+
     #[test]
     fn test_deposit_and_burn() {
         let oldapp = App::default();
@@ -779,6 +5196,7 @@ mod tests {
             deploy_all_contracts(oldapp, user_addr.clone(), owner_addr.clone());
 
         let dep_msg = ExecuteMsg::DepositCollateralAndMint {
+            vault_id: 1,
             token_amount: Uint128::from(1000u128),
         };
 
@@ -792,6 +5210,7 @@ mod tests {
             .unwrap();
 
         let burn_msg = ExecuteMsg::RedeemCollateralAndBurn {
+            vault_id: 1,
             amount_collateral: Uint128::new(1300),
             amount_token: Uint128::new(1000),
         };
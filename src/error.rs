@@ -1,11 +1,38 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{Decimal, StdError, Uint128};
+use cw0::PaymentError;
 use thiserror::Error;
 
+use crate::state::{PauseOp, Role};
+
 #[derive(Error, Debug)]
 pub enum ContractError {
     #[error("{0}")]
     Std(#[from] StdError),
 
+    #[error("{0}")]
+    SemVer(#[from] semver::Error),
+
+    #[error("{0}")]
+    Payment(#[from] PaymentError),
+
+    #[error("A flash mint is already in progress")]
+    FlashMintActive {},
+
+    #[error("Flash mints are limited to {max}")]
+    FlashMintTooLarge { max: Uint128 },
+
+    #[error("Flash mint not repaid: {owed} owed")]
+    FlashMintNotRepaid { owed: Uint128 },
+
+    #[error("Cannot migrate from contract {contract}")]
+    WrongContract { contract: String },
+
+    #[error("Cannot migrate from version {stored} down to {current}")]
+    Downgrade { stored: String, current: String },
+
+    #[error("Migrating from 0.1.0 needs the legacy parameters")]
+    LegacyParamsRequired {},
+
     #[error("InstantiateFailed ")]
     InstantiateFailed {},
 
@@ -24,6 +51,139 @@ pub enum ContractError {
     #[error("Token in set already ")]
     TOKENSET {},
 
+    #[error("Vault is not the minter of token {token}")]
+    NotTokenMinter { token: String },
+
+    #[error("Unknown vault {vault_id}")]
+    UnknownVault { vault_id: u64 },
+
+    #[error("Only the owner of vault {vault_id} may do this")]
+    NotVaultOwner { vault_id: u64 },
+
+    #[error("Only the owner of vault {vault_id} or its operators may do this")]
+    NotVaultOperator { vault_id: u64 },
+
+    #[error("Approval already expired")]
+    InvalidExpiration {},
+
+    #[error("No vault of {collateral} can be redeemed against")]
+    NothingToRedeem { collateral: String },
+
+    #[error("Redemption fee rate {fee_rate} is above the maximum")]
+    RedemptionFeeTooHigh { fee_rate: Decimal },
+
+    #[error("Vault {vault_id} still has debt")]
+    VaultHasDebt { vault_id: u64 },
+
+    #[error("Vault {vault_id} holds less collateral than requested")]
+    InsufficientCollateral { vault_id: u64 },
+
+    #[error("Unknown collateral type: {collateral}")]
+    UnknownCollateral { collateral: String },
+
+    #[error("Invalid collateral type: {reason}")]
+    InvalidCollateralType { reason: String },
+
+    #[error("No stability fees to collect")]
+    NoFees {},
+
+    #[error("Invalid amount")]
+    InvalidAmount {},
+
+    #[error("Collateral {collateral} is not deposited with this asset")]
+    InvalidCollateralAsset { collateral: String },
+
+    #[error("Token {token} is not accepted here")]
+    UnexpectedToken { token: String },
+
+    #[error("Unknown lot: {lot_id}")]
+    UnknownLot { lot_id: u64 },
+
+    #[error("Auction of lot {lot_id} has expired and needs a reset")]
+    AuctionExpired { lot_id: u64 },
+
+    #[error("Auction of lot {lot_id} is still running")]
+    AuctionActive { lot_id: u64 },
+
+    #[error("Lot price {price} is above the maximum accepted")]
+    LotPriceTooHigh { price: Uint128 },
+
+    #[error("Unknown PSM asset: {asset}")]
+    UnknownPsmAsset { asset: String },
+
+    #[error("Invalid PSM asset: {reason}")]
+    InvalidPsmAsset { reason: String },
+
+    #[error("PSM debt ceiling exceeded for {asset}")]
+    PsmCeilingExceeded { asset: String },
+
+    #[error("Sender does not hold the {role} role")]
+    MissingRole { role: Role },
+
+    #[error("Sender is not the pending owner")]
+    NotPendingOwner {},
+
+    #[error("{op} is paused")]
+    Paused { op: PauseOp },
+
+    #[error("System is shut down")]
+    SystemShutdown {},
+
+    #[error("System is not shut down")]
+    NotShutdown {},
+
+    #[error("Settled collateral can be redeemed from {redeemable_at}")]
+    SettlementNotReady { redeemable_at: u64 },
+
+    #[error("Debt is left to settle")]
+    UnsettledDebt {},
+
+    #[error("Redemption rate is not fixed yet")]
+    RatesNotFixed {},
+
+    #[error("Redemption rate is already fixed")]
+    RatesFixed {},
+
+    #[error("No pending update {id}")]
+    UnknownUpdate { id: u64 },
+
+    #[error("Update can be applied from {effective_at}")]
+    UpdateNotReady { effective_at: u64 },
+
+    #[error("Update became effective at {effective_at} and can no longer be cancelled")]
+    UpdateEffective { effective_at: u64 },
+
+    #[error("Invalid config update: {reason}")]
+    InvalidConfigUpdate { reason: String },
+
+    #[error("Debt ceiling of {scope} exceeded")]
+    DebtCeilingExceeded { scope: String },
+
+    #[error("PSM reserve of {asset} is insufficient")]
+    PsmReserveInsufficient { asset: String },
+
+    #[error("Oracle has no price for {symbol}")]
+    OracleUnavailable { symbol: String },
+
+    #[error("Oracle returned an invalid price for {symbol}")]
+    InvalidPrice { symbol: String },
+
+    #[error("Oracle price for {symbol} is stale ({age}s old)")]
+    StalePrice { symbol: String, age: u64 },
+
+    #[error("Oracle price {price} for {symbol} deviates too far from the last accepted price")]
+    PriceDeviation { symbol: String, price: Uint128 },
+
+    #[error("Only {answers} of {quorum} oracle sources answered for {collateral}")]
+    OracleQuorumNotReached {
+        collateral: String,
+        answers: u32,
+        quorum: u32,
+    },
+
+    #[error("Invalid oracle configuration: {reason}")]
+    InvalidOracleConfig { reason: String },
+
     #[error("Custom Error val: {val:?}")]
     CustomError { val: String },
 
@@ -0,0 +1,96 @@
+use cosmwasm_std::{Binary, DepsMut, Env, MessageInfo, Response, SubMsg, Uint128, WasmMsg};
+use cw20::{AllowanceResponse, BalanceResponse, Cw20QueryMsg};
+
+use crate::contract::{burn_stable, mint_stable};
+use crate::error::ContractError;
+use crate::pause::ensure_not_paused;
+use crate::settlement::ensure_live;
+use crate::state::{FlashMint, PauseOp, CONFIG, FLASHMINT, STABLE};
+use crate::surplus::book_surplus;
+
+pub const FLASH_MINT_REPLY_ID: u64 = 2;
+
+/// Mints `amount` mUSD to the sender, a contract, and executes `callback` on
+/// it. By the end of the callback the sender must hold `amount` plus the flash
+/// fee and have granted the vault an allowance for it; the reply burns both,
+/// and fails the whole transaction otherwise.
+pub fn execute_flash_mint(
+    deps: DepsMut,
+    info: MessageInfo,
+    amount: Uint128,
+    callback: Binary,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    ensure_not_paused(deps.storage, PauseOp::Mint, None)?;
+    if FLASHMINT.may_load(deps.storage)?.is_some() {
+        return Err(ContractError::FlashMintActive {});
+    }
+    if amount.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+    let config = CONFIG.load(deps.storage)?;
+    if amount > config.max_flash_mint {
+        return Err(ContractError::FlashMintTooLarge {
+            max: config.max_flash_mint,
+        });
+    }
+
+    let fee = amount * config.flash_fee;
+    FLASHMINT.save(
+        deps.storage,
+        &FlashMint {
+            borrower: info.sender.clone(),
+            amount,
+            fee,
+        },
+    )?;
+
+    let token = STABLE.load(deps.storage)?;
+    Ok(Response::new()
+        .add_message(mint_stable(info.sender.clone(), amount, token))
+        .add_submessage(SubMsg::reply_on_success(
+            WasmMsg::Execute {
+                contract_addr: info.sender.to_string(),
+                msg: callback,
+                funds: vec![],
+            },
+            FLASH_MINT_REPLY_ID,
+        ))
+        .add_attribute("action", "flash_mint")
+        .add_attribute("borrower", info.sender)
+        .add_attribute("amount", amount)
+        .add_attribute("fee", fee))
+}
+
+/// Checks that the borrower can repay the flash mint plus fee, burns it and
+/// books the fee as surplus.
+pub fn reply_flash_mint(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let flash_mint = FLASHMINT.load(deps.storage)?;
+    FLASHMINT.remove(deps.storage);
+    let owed = flash_mint.amount + flash_mint.fee;
+
+    let token = STABLE.load(deps.storage)?;
+    let balance: BalanceResponse = deps.querier.query_wasm_smart(
+        token.clone(),
+        &Cw20QueryMsg::Balance {
+            address: flash_mint.borrower.to_string(),
+        },
+    )?;
+    let allowance: AllowanceResponse = deps.querier.query_wasm_smart(
+        token.clone(),
+        &Cw20QueryMsg::Allowance {
+            owner: flash_mint.borrower.to_string(),
+            spender: env.contract.address.to_string(),
+        },
+    )?;
+    if balance.balance < owed || allowance.allowance < owed {
+        return Err(ContractError::FlashMintNotRepaid { owed });
+    }
+    book_surplus(deps.storage, flash_mint.fee)?;
+
+    Ok(Response::new()
+        .add_message(burn_stable(flash_mint.borrower.clone(), owed, token))
+        .add_attribute("action", "repay_flash_mint")
+        .add_attribute("borrower", flash_mint.borrower)
+        .add_attribute("burned", owed))
+}
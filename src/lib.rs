@@ -1,7 +1,23 @@
+pub mod access;
+pub mod auction;
 pub mod contract;
 mod error;
+pub mod flash_mint;
 pub mod helpers;
+pub mod migrate;
 pub mod msg;
+pub mod operators;
+pub mod oracle;
+pub mod pause;
+pub mod psm;
+pub mod rates;
+pub mod redemption;
+pub mod savings;
+pub mod settlement;
+pub mod stability_pool;
 pub mod state;
+pub mod surplus;
+pub mod timelock;
+pub mod twap;
 
 pub use crate::error::ContractError;
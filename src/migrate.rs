@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+use cosmwasm_std::{Addr, Decimal, DepsMut, Env, Order, StdResult, Uint128};
+use cw20::Denom;
+use cw_storage_plus::{Item, Map};
+use schemars::JsonSchema;
+use semver::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::contract::save_position;
+use crate::error::ContractError;
+use crate::msg::MigrateMsg;
+use crate::state::{
+    CollateralRate, CollateralType, Config, Position, ACCRUEDFEES, BADDEBT, COLLATERALTYPES,
+    CONFIG, RATES, VAULTCOUNT,
+};
+
+/// Config of 0.1.0, which held a single collateral.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+struct LegacyConfig {
+    owner: Addr,
+    oracle: Addr,
+    denom: String,
+    min_threashold: Uint128,
+    liquidity_threashold: Uint128,
+    token_set: bool,
+}
+
+const LEGACYCONFIG: Item<LegacyConfig> = Item::new("config");
+/// Collateral deposited per user, before 0.2.0.
+const COLLATERALDEPOSITED: Map<Addr, Uint128> = Map::new("collateradeposited");
+/// Debt per user, before 0.2.0. There were no stability fees yet, so it is
+/// also the normalized debt at a rate of one.
+const TOKENSMINTED: Map<Addr, Uint128> = Map::new("tokensminted");
+/// Symbol 0.1.0 asked the oracle for.
+const LEGACY_ORACLE_SYMBOL: &str = "OM";
+
+type Migration = fn(DepsMut, &Env, &MigrateMsg) -> Result<(), ContractError>;
+
+/// State migrations in the order they were introduced, each tagged with the
+/// version that first needs it.
+const MIGRATIONS: &[(&str, Migration)] = &[("0.2.0", migrate_single_collateral)];
+
+/// Runs, oldest first, every migration introduced after `stored` and up to
+/// `current`. Returns how many ran.
+pub fn run_migrations(
+    mut deps: DepsMut,
+    env: &Env,
+    msg: &MigrateMsg,
+    stored: &Version,
+    current: &Version,
+) -> Result<u32, ContractError> {
+    let mut applied = 0;
+    for (version, migration) in MIGRATIONS {
+        let version: Version = version.parse()?;
+        if *stored < version && version <= *current {
+            migration(deps.branch(), env, msg)?;
+            applied += 1;
+        }
+    }
+    Ok(applied)
+}
+
+/// Converts the single collateral state of 0.1.0: the old config becomes the
+/// new one plus a collateral type built from its denom and thresholds, and the
+/// separate collateral and debt maps, keyed by user, become numbered vaults
+/// of that type.
+fn migrate_single_collateral(
+    deps: DepsMut,
+    env: &Env,
+    msg: &MigrateMsg,
+) -> Result<(), ContractError> {
+    let params = msg
+        .legacy
+        .as_ref()
+        .ok_or(ContractError::LegacyParamsRequired {})?;
+    let legacy = LEGACYCONFIG.load(deps.storage)?;
+
+    let config = Config {
+        owner: legacy.owner,
+        oracle: legacy.oracle,
+        treasury: deps.api.addr_validate(&params.treasury)?,
+        token_set: legacy.token_set,
+        max_price_age: params.max_price_age,
+        max_price_deviation: params.max_price_deviation,
+        twap_window: params.twap_window,
+        debt_ceiling: params.debt_ceiling,
+        settlement_delay: params.settlement_delay,
+        update_delay: params.update_delay,
+        flash_fee: params.flash_fee,
+        max_flash_mint: params.max_flash_mint,
+    };
+    CONFIG.save(deps.storage, &config)?;
+    ACCRUEDFEES.save(deps.storage, &Uint128::zero())?;
+    BADDEBT.save(deps.storage, &Uint128::zero())?;
+
+    let collateral = params.collateral.clone();
+    let collateral_type = CollateralType {
+        denom: Denom::Native(legacy.denom),
+        oracle_symbol: LEGACY_ORACLE_SYMBOL.to_string(),
+        min_threashold: legacy.min_threashold,
+        liquidity_threashold: legacy.liquidity_threashold,
+        debt_ceiling: params.collateral_debt_ceiling,
+        stability_fee: Decimal::zero(),
+        liquidation_penalty: params.liquidation_penalty,
+        auction: params.auction.clone(),
+    };
+    COLLATERALTYPES.save(deps.storage, collateral.clone(), &collateral_type)?;
+    RATES.save(
+        deps.storage,
+        collateral.clone(),
+        &CollateralRate {
+            rate: Decimal::one(),
+            last_updated: env.block.time.seconds(),
+        },
+    )?;
+
+    let mut merged: BTreeMap<Addr, Position> = BTreeMap::new();
+    let deposits = COLLATERALDEPOSITED
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let debts = TOKENSMINTED
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (owner, amount) in deposits {
+        COLLATERALDEPOSITED.remove(deps.storage, owner.clone());
+        legacy_position(&mut merged, owner, &collateral).collateral = amount;
+    }
+    for (owner, debt) in debts {
+        TOKENSMINTED.remove(deps.storage, owner.clone());
+        legacy_position(&mut merged, owner, &collateral).debt = debt;
+    }
+
+    let mut vault_id = VAULTCOUNT.may_load(deps.storage)?.unwrap_or_default();
+    for position in merged.into_values() {
+        vault_id += 1;
+        save_position(deps.storage, vault_id, &position)?;
+    }
+    VAULTCOUNT.save(deps.storage, &vault_id)?;
+    Ok(())
+}
+
+fn legacy_position<'a>(
+    merged: &'a mut BTreeMap<Addr, Position>,
+    owner: Addr,
+    collateral: &str,
+) -> &'a mut Position {
+    merged.entry(owner.clone()).or_insert(Position {
+        owner,
+        collateral_type: collateral.to_string(),
+        collateral: Uint128::zero(),
+        debt: Uint128::zero(),
+    })
+}
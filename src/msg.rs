@@ -1,55 +1,350 @@
-use cosmwasm_std::{Addr, Uint128};
+use cosmwasm_std::{Addr, Binary, Decimal, Uint128};
+use cw20::{Cw20ReceiveMsg, Expiration};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::state::{
+    AuctionParams, CollateralType, ConfigUpdate, Lot, OracleAdapter, OracleConfig, PauseOp,
+    PendingUpdate, PsmAsset, Role, Shutdown,
+};
+
+/// Page size of list queries called without a `limit`.
+pub const DEFAULT_LIMIT: u32 = 10;
+/// Largest page size of list queries.
+pub const MAX_LIMIT: u32 = 30;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct InstantiateMsg {
     pub owner: String,
     pub oracle: String,
-    pub denom: String,
-    pub min_threashold: Uint128,
-    pub liquidity_threashold: Uint128,
+    pub treasury: String,
+    pub max_price_age: u64,
+    pub max_price_deviation: Decimal,
+    pub twap_window: u64,
+    pub debt_ceiling: Uint128,
+    pub settlement_delay: u64,
+    pub update_delay: u64,
+    pub flash_fee: Decimal,
+    pub max_flash_mint: Uint128,
+    /// cw20 token to instantiate as mUSD. Without it the token is registered
+    /// afterwards with `SetToken`.
+    pub stable_token: Option<StableTokenInfo>,
+}
+
+/// cw20 token the vault instantiates as mUSD, with itself as sole minter.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct StableTokenInfo {
+    pub code_id: u64,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
+    /// Registers an existing cw20 as mUSD when none was instantiated. The
+    /// vault must be its minter. Admin.
     SetToken {
         token: Addr,
     },
-    DepositCollateral {},
+    /// Entry point for cw20 `Send`, carrying a `ReceiveMsg`.
+    Receive(Cw20ReceiveMsg),
+    /// Registers a new collateral type. Its parameters change through `UpdateConfig`.
+    SetCollateralType {
+        collateral: String,
+        collateral_type: CollateralType,
+    },
+    /// Registers the price sources of a collateral type without vaults yet.
+    /// Later changes go through `UpdateConfig`.
+    SetOracleSources {
+        collateral: String,
+        oracle_config: OracleConfig,
+    },
+    /// Queues parameter changes, applied with `ApplyUpdate` once `update_delay`
+    /// has passed. Risk manager, plus oracle manager to change the oracle or
+    /// price sources and fee collector to change the treasury.
+    UpdateConfig {
+        update: ConfigUpdate,
+    },
+    /// Drops a queued update before it becomes effective. Risk manager.
+    CancelUpdate {
+        id: u64,
+    },
+    /// Applies a queued update whose delay has passed. Open to anyone.
+    ApplyUpdate {
+        id: u64,
+    },
+    /// Gives `role` to `address`. Admin, or owner for the admin role.
+    GrantRole {
+        role: Role,
+        address: String,
+    },
+    /// Takes `role` away from `address`. Admin, or owner for the admin role.
+    RevokeRole {
+        role: Role,
+        address: String,
+    },
+    /// Offers ownership to `new_owner`, who takes it over with `AcceptOwnership`.
+    TransferOwnership {
+        new_owner: String,
+    },
+    AcceptOwnership {},
+    /// Pauses `ops` for one collateral type, or for all when `collateral` is
+    /// `None`. Guardian.
+    Pause {
+        collateral: Option<String>,
+        ops: Vec<PauseOp>,
+    },
+    /// Lifts a pause set with `Pause`. Admin.
+    Unpause {
+        collateral: Option<String>,
+        ops: Vec<PauseOp>,
+    },
+    /// Starts global settlement: freezes prices and stops minting and liquidations.
+    Shutdown {},
+    /// Cancels the debt of a vault against its collateral at the frozen price.
+    SettleVault {
+        vault_id: u64,
+    },
+    /// Settles the debt of an auction lot left open by the shutdown at the
+    /// frozen price and returns the rest of its collateral to the vault owner.
+    SettleLot {
+        lot_id: u64,
+    },
+    /// Settles the caller's vault and withdraws the collateral left over.
+    WithdrawExcessCollateral {
+        vault_id: u64,
+    },
+    /// Fixes the redemption rate once every vault and lot is settled.
+    FixRates {},
+    /// Burns mUSD for a pro-rata share of the settled collateral and PSM reserves.
+    RedeemSettled {
+        amount: Uint128,
+    },
+    /// Records a price observation for the TWAP of a collateral type. Open to anyone.
+    Poke {
+        collateral: String,
+    },
+    /// Mints the stability fees accrued so far to the treasury.
+    CollectFees {},
+    /// Opens an empty vault of `collateral` owned by the sender. The new id is
+    /// returned in the `vault_id` attribute.
+    OpenVault {
+        collateral: String,
+    },
+    /// Closes a vault without debt and returns its collateral. Owner only.
+    CloseVault {
+        vault_id: u64,
+    },
+    /// Hands a vault with its collateral and debt to `recipient`. Owner only.
+    TransferVault {
+        vault_id: u64,
+        recipient: String,
+    },
+    /// Lets `operator` deposit collateral into and repay the debt of every
+    /// vault of the sender, until `expires` or forever when omitted.
+    ApproveOperator {
+        operator: String,
+        expires: Option<Expiration>,
+    },
+    RevokeOperator {
+        operator: String,
+    },
+    DepositCollateral {
+        vault_id: u64,
+    },
     DepositCollateralAndMint {
+        vault_id: u64,
         token_amount: Uint128,
     },
     BorrowTokens {
+        vault_id: u64,
         token_amount: Uint128,
     },
     RedeemCollateral {
+        vault_id: u64,
         amount: Uint128,
     },
     RedeemCollateralAndBurn {
+        vault_id: u64,
         amount_collateral: Uint128,
         amount_token: Uint128,
     },
     Repay {
+        vault_id: u64,
         token_amount: Uint128,
     },
 
+    /// Moves up to `amount_token` of an unsafe vault's debt, with a proportional
+    /// share of its collateral, into a new auction lot.
     Liquidate {
-        user: Addr,
+        vault_id: u64,
         amount_token: Uint128,
     },
-    Swap {
-        amount_token: Uint128,
+    /// Buys up to `amount` collateral from a lot at its current price, paying mUSD.
+    TakeLot {
+        lot_id: u64,
+        amount: Uint128,
+        max_price: Uint128,
+    },
+    /// Restarts the price curve of an expired lot from the oracle price.
+    ResetLot {
+        lot_id: u64,
+    },
+    /// Burns up to `amount` mUSD for collateral taken from the vaults of
+    /// `collateral` with the lowest collateral ratio first, minus a fee that
+    /// must not be above `max_fee_rate`. Needs an mUSD allowance.
+    Redeem {
+        collateral: String,
+        amount: Uint128,
+        max_fee_rate: Decimal,
+    },
+    /// Moves `amount` mUSD into the stability pool. Needs an mUSD allowance.
+    DepositToPool {
+        amount: Uint128,
+    },
+    /// Takes up to `amount` mUSD left of the sender's deposit out of the pool.
+    WithdrawFromPool {
+        amount: Uint128,
+    },
+    /// Pays out the collateral the sender's pool deposit earned.
+    ClaimCollateralGains {},
+    /// Burns `amount` mUSD into the sender's savings, earning the savings rate.
+    /// Needs an mUSD allowance.
+    Join {
+        amount: Uint128,
+    },
+    /// Mints up to `amount` mUSD of the sender's savings back to it.
+    Exit {
+        amount: Uint128,
+    },
+    /// Mints `amount` mUSD to the sending contract and executes `callback` on
+    /// it. The contract must leave the vault an allowance for `amount` plus the
+    /// flash fee, which is burned once the callback returns.
+    FlashMint {
+        amount: Uint128,
+        callback: Binary,
+    },
+    /// Whitelists a stable asset in the peg stability module or updates its parameters.
+    SetPsmAsset {
+        asset: String,
+        psm_asset: PsmAsset,
+    },
+    /// Swaps `amount` of a PSM asset into mUSD. Native assets are sent as funds,
+    /// cw20 assets need an allowance for the contract.
+    PsmSell {
+        asset: String,
+        amount: Uint128,
+    },
+    /// Swaps mUSD into `amount` of a PSM asset.
+    PsmBuy {
+        asset: String,
+        amount: Uint128,
+    },
+}
+
+/// Hook messages embedded in a cw20 `Send` to the vault.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiveMsg {
+    /// Deposits the sent cw20 collateral into a vault.
+    DepositCollateral { vault_id: u64 },
+    /// Deposits the sent cw20 collateral and mints `token_amount` mUSD against it.
+    DepositAndMint {
+        vault_id: u64,
+        token_amount: Uint128,
     },
+    /// Repays vault debt with the sent mUSD. Anything above the debt is sent back.
+    Repay { vault_id: u64 },
+    /// Redeems the sent mUSD during global settlement.
+    RedeemSettled {},
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum QueryMsg {
-    Info { user: Addr },
+    /// Collateral, debt and health factor of a vault.
+    Info {
+        vault_id: u64,
+    },
+    /// Vaults in id order, `limit` at a time.
+    AllPositions {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Vaults of `owner` in id order, `limit` at a time.
+    PositionsByOwner {
+        owner: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    /// Up to `limit` vaults whose health factor is below the minimum of their
+    /// collateral type at the TWAP, and can be liquidated.
+    UnsafePositions {
+        limit: Option<u32>,
+    },
+    /// mUSD the owner of a vault can borrow against it now.
+    MaxBorrow {
+        vault_id: u64,
+    },
+    /// Collateral the owner of a vault can withdraw from it now.
+    MaxWithdraw {
+        vault_id: u64,
+    },
+    /// What `Liquidate` with the same arguments would do in the current block.
+    SimulateLiquidation {
+        vault_id: u64,
+        amount_token: Uint128,
+    },
     Config {},
+    CollateralType {
+        collateral: String,
+    },
+    CollateralTypes {},
+    Lot {
+        lot_id: u64,
+    },
+    Lots {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    PsmAsset {
+        asset: String,
+    },
+    PsmAssets {},
+    /// Answers of every price source of a collateral type and their aggregate.
+    OraclePrices {
+        collateral: String,
+    },
+    /// Global settlement state, with frozen prices and settlement pools.
+    Settlement {},
+    /// Operations paused globally and per collateral type.
+    PauseState {},
+    /// Owner, pending owner and the holders of every role.
+    Roles {},
+    /// Parameter changes queued by `UpdateConfig`.
+    PendingUpdates {},
+    /// Current base rate and fee rate of redemptions.
+    RedemptionRate {},
+    /// mUSD in the stability pool and the collateral it holds for depositors.
+    StabilityPool {},
+    /// Protocol surplus and bad debt, next to the debt backing the mUSD supply.
+    SystemBalance {},
+    /// mUSD an address holds in savings, with the savings rate.
+    SavingsBalance {
+        address: String,
+    },
+    /// What is left of a stability pool deposit and its unclaimed gains.
+    PoolDeposit {
+        depositor: String,
+    },
+    /// Unexpired operators approved by a vault owner.
+    Operators {
+        owner: String,
+    },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -61,21 +356,303 @@ pub struct CustomResponse {
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct InfoResponse {
+    pub vault_id: u64,
+    pub owner: Addr,
+    pub collateral: String,
     pub collateral_deposited: Uint128,
     pub total_debt: Uint128,
     pub health_factor: Uint128,
+    /// Price below which the TWAP makes the vault liquidatable, if it has debt.
+    pub liquidation_price: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct MaxBorrowResponse {
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct MaxWithdrawResponse {
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct PositionsResponse {
+    pub positions: Vec<InfoResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SimulateLiquidationResponse {
+    /// mUSD debt that would be taken off the vault.
+    pub debt: Uint128,
+    /// Collateral that would be taken out of the vault.
+    pub seized: Uint128,
+    /// Part of the debt the stability pool would absorb.
+    pub pool_debt: Uint128,
+    /// Collateral the stability pool would receive for it.
+    pub pool_collateral: Uint128,
+    /// Liquidation penalty the lot would charge on top of the rest of the debt.
+    pub bonus: Uint128,
+    /// mUSD debt left in the vault.
+    pub remaining_debt: Uint128,
+    /// Health factor of the vault afterwards.
+    pub health_factor: Uint128,
+    /// Why the liquidation would fail, if it would.
+    pub error: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub struct ConfigResponse {
     pub owner: Addr,
+    pub stable_token: Option<Addr>,
+    pub oracle: Addr,
+    pub treasury: Addr,
+    pub max_price_age: u64,
+    pub max_price_deviation: Decimal,
+    pub twap_window: u64,
+    pub debt_ceiling: Uint128,
+    pub settlement_delay: u64,
+    pub update_delay: u64,
+    pub flash_fee: Decimal,
+    pub max_flash_mint: Uint128,
+    /// mUSD currently outstanding against `debt_ceiling`.
+    pub total_debt: Uint128,
+    pub accrued_fees: Uint128,
+    pub bad_debt: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct CollateralTypeResponse {
+    pub collateral: String,
+    pub collateral_type: CollateralType,
     pub total_collateral: Uint128,
-    pub oracle_price: Uint128,
-    pub fees: Uint128,
-    pub liquidity_threashold: Uint128,
+    pub total_debt: Uint128,
+    pub rate: Decimal,
+    /// Validated oracle price, `None` if the feed is currently rejected.
+    pub oracle_price: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct CollateralTypesResponse {
+    pub collateral_types: Vec<CollateralTypeResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct LotResponse {
+    pub lot_id: u64,
+    pub lot: Lot,
+    pub price: Uint128,
+    pub expired: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct LotsResponse {
+    pub lots: Vec<LotResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct PsmAssetResponse {
+    pub asset: String,
+    pub psm_asset: PsmAsset,
+    pub reserve: Uint128,
+    /// Amount that can still be swapped in before hitting the debt ceiling.
+    pub available: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct PsmAssetsResponse {
+    pub assets: Vec<PsmAssetResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SourcePriceResponse {
+    pub adapter: OracleAdapter,
+    pub price: Option<Uint128>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct OraclePricesResponse {
+    pub collateral: String,
+    pub sources: Vec<SourcePriceResponse>,
+    pub fallback: Option<SourcePriceResponse>,
+    /// Price the vault would use, `None` if it is currently rejected.
+    pub aggregate: Option<Uint128>,
+    pub error: Option<String>,
+    /// Time-weighted average over `twap_window`, `None` before the first observation.
+    pub twap: Option<Uint128>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SettledCollateralResponse {
+    pub collateral: String,
+    /// Price frozen at shutdown.
+    pub price: Uint128,
+    /// Collateral taken from settled positions and not yet redeemed.
+    pub pool: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SettlementResponse {
+    /// `None` while the system is live.
+    pub shutdown: Option<Shutdown>,
+    pub collateral: Vec<SettledCollateralResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct CollateralPausedResponse {
+    pub collateral: String,
+    pub ops: Vec<PauseOp>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct PauseStateResponse {
+    /// Operations paused for every collateral type.
+    pub global: Vec<PauseOp>,
+    /// Collateral types with operations paused on their own.
+    pub collateral: Vec<CollateralPausedResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct RoleMembersResponse {
+    pub role: Role,
+    pub members: Vec<Addr>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
-pub enum MigrateMsg {}
+pub struct RolesResponse {
+    pub owner: Addr,
+    pub pending_owner: Option<Addr>,
+    pub roles: Vec<RoleMembersResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct MigrateMsg {
+    /// Parameters missing from the 0.1.0 state, required when migrating from it.
+    pub legacy: Option<LegacyParams>,
+}
+
+/// Settings of 0.2.0 that a 0.1.0 contract has no value for. Its collateral
+/// is registered as `collateral`, with the denom and thresholds of the old
+/// config, the `OM` oracle symbol and no stability fee.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct LegacyParams {
+    pub treasury: String,
+    pub max_price_age: u64,
+    pub max_price_deviation: Decimal,
+    pub twap_window: u64,
+    pub debt_ceiling: Uint128,
+    pub settlement_delay: u64,
+    pub update_delay: u64,
+    pub flash_fee: Decimal,
+    pub max_flash_mint: Uint128,
+    pub collateral: String,
+    pub collateral_debt_ceiling: Uint128,
+    pub liquidation_penalty: Decimal,
+    pub auction: AuctionParams,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct PendingUpdatesResponse {
+    pub updates: Vec<PendingUpdate>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct OperatorResponse {
+    pub operator: Addr,
+    pub expires: Expiration,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct OperatorsResponse {
+    pub operators: Vec<OperatorResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct RedemptionRateResponse {
+    pub base_rate: Decimal,
+    /// Fee rate before the increase of the base rate by the redemption itself.
+    pub fee_rate: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct CollateralGainResponse {
+    pub collateral: String,
+    pub amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct StabilityPoolResponse {
+    /// mUSD in the pool.
+    pub total: Uint128,
+    /// Times the pool was emptied by a liquidation.
+    pub epoch: u64,
+    /// Collateral seized by the pool and not yet claimed.
+    pub collateral: Vec<CollateralGainResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct PoolDepositResponse {
+    /// mUSD left of the deposit.
+    pub deposit: Uint128,
+    /// Collateral earned and not yet claimed.
+    pub gains: Vec<CollateralGainResponse>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SystemBalanceResponse {
+    /// Fees and penalties owed to the protocol and not yet minted to the
+    /// treasury, net of bad debt.
+    pub surplus: Uint128,
+    /// mUSD in circulation that nothing backs, net of surplus.
+    pub bad_debt: Uint128,
+    /// mUSD owed by vaults, plus the mUSD minted against PSM reserves.
+    pub total_debt: Uint128,
+    /// Debt of open auction lots.
+    pub auction_debt: Uint128,
+    /// mUSD held in savings, interest included.
+    pub savings: Uint128,
+    /// mUSD supply.
+    pub supply: Uint128,
+    /// Whether the surplus covers all bad debt.
+    pub fully_backed: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub struct SavingsBalanceResponse {
+    /// mUSD in savings, interest included.
+    pub balance: Uint128,
+    /// Annual savings rate.
+    pub rate: Decimal,
+    pub chi: Decimal,
+}
@@ -0,0 +1,101 @@
+use cosmwasm_std::{
+    Addr, BlockInfo, Deps, DepsMut, Env, MessageInfo, Order, Response, StdResult, Storage,
+};
+use cw20::Expiration;
+
+use crate::contract::{load_owned_position, save_position};
+use crate::error::ContractError;
+use crate::msg::{OperatorResponse, OperatorsResponse};
+use crate::state::OPERATORS;
+
+/// Hands a vault, with its collateral and debt, to `recipient`. Owner only.
+/// Operators of the previous owner lose access to it.
+pub fn execute_transfer_vault(
+    deps: DepsMut,
+    info: MessageInfo,
+    vault_id: u64,
+    recipient: String,
+) -> Result<Response, ContractError> {
+    let mut position = load_owned_position(deps.storage, vault_id, &info.sender)?;
+    let recipient = deps.api.addr_validate(&recipient)?;
+    position.owner = recipient.clone();
+    save_position(deps.storage, vault_id, &position)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "transfer_vault")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("from", info.sender)
+        .add_attribute("to", recipient))
+}
+
+/// Lets `operator` deposit collateral into and repay the debt of every vault
+/// of the sender until `expires`. A new approval replaces the previous one.
+pub fn execute_approve_operator(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    operator: String,
+    expires: Option<Expiration>,
+) -> Result<Response, ContractError> {
+    let operator = deps.api.addr_validate(&operator)?;
+    let expires = expires.unwrap_or_default();
+    if expires.is_expired(&env.block) {
+        return Err(ContractError::InvalidExpiration {});
+    }
+    OPERATORS.save(
+        deps.storage,
+        (info.sender.clone(), operator.clone()),
+        &expires,
+    )?;
+
+    Ok(Response::new()
+        .add_attribute("action", "approve_operator")
+        .add_attribute("owner", info.sender)
+        .add_attribute("operator", operator)
+        .add_attribute("expires", expires.to_string()))
+}
+
+pub fn execute_revoke_operator(
+    deps: DepsMut,
+    info: MessageInfo,
+    operator: String,
+) -> Result<Response, ContractError> {
+    let operator = deps.api.addr_validate(&operator)?;
+    OPERATORS.remove(deps.storage, (info.sender.clone(), operator.clone()));
+
+    Ok(Response::new()
+        .add_attribute("action", "revoke_operator")
+        .add_attribute("owner", info.sender)
+        .add_attribute("operator", operator))
+}
+
+/// Whether `operator` holds an unexpired approval from `owner`.
+pub fn is_operator(
+    storage: &dyn Storage,
+    block: &BlockInfo,
+    owner: &Addr,
+    operator: &Addr,
+) -> StdResult<bool> {
+    Ok(OPERATORS
+        .may_load(storage, (owner.clone(), operator.clone()))?
+        .map(|expires| !expires.is_expired(block))
+        .unwrap_or(false))
+}
+
+/// Unexpired operators approved by `owner`.
+pub fn query_operators(deps: Deps, env: Env, owner: String) -> StdResult<OperatorsResponse> {
+    let owner = deps.api.addr_validate(&owner)?;
+    let operators = OPERATORS
+        .prefix(owner)
+        .range(deps.storage, None, None, Order::Ascending)
+        .filter(|item| match item {
+            Ok((_, expires)) => !expires.is_expired(&env.block),
+            Err(_) => true,
+        })
+        .map(|item| {
+            let (operator, expires) = item?;
+            Ok(OperatorResponse { operator, expires })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(OperatorsResponse { operators })
+}
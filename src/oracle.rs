@@ -0,0 +1,433 @@
+use cosmwasm_std::{
+    Decimal, Deps, DepsMut, Env, QueryRequest, Response, StdResult, Storage, Uint128, WasmQuery,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::contract::load_collateral_type;
+use crate::error::ContractError;
+use crate::msg::{OraclePricesResponse, SourcePriceResponse};
+use crate::settlement::ensure_live;
+use crate::state::{
+    Aggregation, CollateralType, Config, OracleAdapter, OracleConfig, OracleSource, PriceRecord,
+    COLLATERALTYPES, CONFIG, LASTPRICES, ORACLESOURCES,
+};
+use crate::twap::{record_observation, twap_price};
+
+/// Price query of the hongbai oracle, the only one of its messages the vault
+/// sends.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum OracleQuery {
+    GetPrice { symbol: String },
+}
+
+/// Fields of the oracle price response the vault relies on. `timestamp` is when
+/// the feed was last updated, in seconds; feeds that do not report it are
+/// treated as updated in the current block.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeedPrice {
+    pub price: u128,
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+}
+
+/// Sources registered for `collateral`, or the configured hongbai oracle alone
+/// when none were registered.
+pub fn load_oracle_config(
+    deps: Deps,
+    config: &Config,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> StdResult<OracleConfig> {
+    Ok(ORACLESOURCES
+        .may_load(deps.storage, collateral.to_string())?
+        .unwrap_or_else(|| OracleConfig {
+            sources: vec![OracleSource {
+                adapter: OracleAdapter::Hongbai {
+                    contract: config.oracle.clone(),
+                    symbol: collateral_type.oracle_symbol.clone(),
+                },
+                weight: 1,
+            }],
+            aggregation: Aggregation::Median,
+            quorum: 1,
+            fallback: None,
+        }))
+}
+
+fn adapter_label(adapter: &OracleAdapter) -> String {
+    match adapter {
+        OracleAdapter::Hongbai { symbol, .. } => symbol.clone(),
+        OracleAdapter::Generic { contract, .. } => contract.to_string(),
+    }
+}
+
+/// Asks a single source for its price and rejects zero or stale answers.
+fn source_price(
+    deps: Deps,
+    env: &Env,
+    config: &Config,
+    adapter: &OracleAdapter,
+) -> Result<Uint128, ContractError> {
+    let label = adapter_label(adapter);
+    let feed: StdResult<FeedPrice> = match adapter {
+        OracleAdapter::Hongbai { contract, symbol } => deps.querier.query_wasm_smart(
+            contract,
+            &OracleQuery::GetPrice {
+                symbol: symbol.clone(),
+            },
+        ),
+        OracleAdapter::Generic { contract, query } => {
+            deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
+                contract_addr: contract.to_string(),
+                msg: query.clone(),
+            }))
+        }
+    };
+    let feed = feed.map_err(|_| ContractError::OracleUnavailable {
+        symbol: label.clone(),
+    })?;
+
+    let price = Uint128::new(feed.price);
+    if price.is_zero() {
+        return Err(ContractError::InvalidPrice { symbol: label });
+    }
+
+    let now = env.block.time.seconds();
+    let age = now.saturating_sub(feed.timestamp.unwrap_or(now));
+    if age > config.max_price_age {
+        return Err(ContractError::StalePrice { symbol: label, age });
+    }
+
+    Ok(price)
+}
+
+/// Combines fresh answers, given as (price, weight), into a single price.
+fn aggregate(answers: &mut [(Uint128, u64)], aggregation: &Aggregation) -> Uint128 {
+    match aggregation {
+        Aggregation::Median => {
+            answers.sort_by(|a, b| a.0.cmp(&b.0));
+            let middle = answers.len() / 2;
+            if answers.len() % 2 == 0 {
+                (answers[middle - 1].0 + answers[middle].0) / Uint128::new(2)
+            } else {
+                answers[middle].0
+            }
+        }
+        Aggregation::Weighted => {
+            let total_weight: u64 = answers.iter().map(|(_, weight)| weight).sum();
+            let weighted_sum = answers
+                .iter()
+                .fold(Uint128::zero(), |sum, (price, weight)| {
+                    sum + *price * Uint128::from(*weight)
+                });
+            weighted_sum / Uint128::from(total_weight)
+        }
+    }
+}
+
+/// Aggregates the registered sources, falling back to the secondary source
+/// when too few of them give a valid answer. Also returns the labels of the
+/// sources the price came from.
+fn aggregate_price(
+    deps: Deps,
+    env: &Env,
+    config: &Config,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<(Uint128, String), ContractError> {
+    let oracle_config = load_oracle_config(deps, config, collateral, collateral_type)?;
+
+    let mut labels = vec![];
+    let mut answers = oracle_config
+        .sources
+        .iter()
+        .filter_map(|source| {
+            let price = source_price(deps, env, config, &source.adapter).ok()?;
+            labels.push(adapter_label(&source.adapter));
+            Some((price, source.weight))
+        })
+        .collect::<Vec<_>>();
+    if !answers.is_empty() && answers.len() as u32 >= oracle_config.quorum {
+        let price = aggregate(&mut answers, &oracle_config.aggregation);
+        return Ok((price, labels.join(",")));
+    }
+
+    match &oracle_config.fallback {
+        Some(fallback) => Ok((
+            source_price(deps, env, config, fallback)?,
+            adapter_label(fallback),
+        )),
+        None => Err(ContractError::OracleQuorumNotReached {
+            collateral: collateral.to_string(),
+            answers: answers.len() as u32,
+            quorum: oracle_config.quorum,
+        }),
+    }
+}
+
+/// Fetches the price of `collateral` and checks it against the configured
+/// staleness and deviation bounds. Nothing is written, see `refresh_price`.
+pub fn query_price(
+    deps: Deps,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let now = env.block.time.seconds();
+
+    let (price, label) = aggregate_price(deps, env, &config, collateral, collateral_type)?;
+
+    // only compare against a cached price that is itself still fresh, so the
+    // feed can recover after a long outage
+    if let Some(last) = LASTPRICES.may_load(deps.storage, collateral.to_string())? {
+        if now.saturating_sub(last.updated_at) <= config.max_price_age {
+            let difference = if price > last.price {
+                price - last.price
+            } else {
+                last.price - price
+            };
+            let deviation = Decimal::from_ratio(difference, last.price);
+            if deviation > config.max_price_deviation {
+                return Err(ContractError::PriceDeviation {
+                    symbol: label,
+                    price,
+                });
+            }
+        }
+    }
+
+    Ok(price)
+}
+
+/// Same as `query_price`, then caches the accepted price and records it as a
+/// TWAP observation.
+pub fn refresh_price(
+    deps: DepsMut,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    let price = query_price(deps.as_ref(), env, collateral, collateral_type)?;
+    LASTPRICES.save(
+        deps.storage,
+        collateral.to_string(),
+        &PriceRecord {
+            price,
+            updated_at: env.block.time.seconds(),
+        },
+    )?;
+    let config = CONFIG.load(deps.storage)?;
+    record_observation(deps.storage, env, collateral, price, config.twap_window)?;
+    Ok(price)
+}
+
+/// Records an observation when the oracle answers. Used by operations that do
+/// not need a price, so they never fail because of the oracle.
+pub fn observe_price(deps: DepsMut, env: &Env, collateral: &str, collateral_type: &CollateralType) {
+    refresh_price(deps, env, collateral, collateral_type).ok();
+}
+
+/// Pairs the spot price with the TWAP of `collateral`, which falls back to the
+/// spot price before the first observation.
+fn with_twap(
+    storage: &dyn Storage,
+    env: &Env,
+    collateral: &str,
+    spot: Uint128,
+) -> Result<(Uint128, Uint128), ContractError> {
+    let config = CONFIG.load(storage)?;
+    let twap = twap_price(storage, env, collateral, config.twap_window)?.unwrap_or(spot);
+    Ok((spot, twap))
+}
+
+/// Refreshes the price and returns the TWAP, which liquidations are decided on
+/// so that a single spike of the feed cannot liquidate healthy positions.
+pub fn refresh_twap(
+    mut deps: DepsMut,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    let spot = refresh_price(deps.branch(), env, collateral, collateral_type)?;
+    let (_, twap) = with_twap(deps.storage, env, collateral, spot)?;
+    Ok(twap)
+}
+
+/// What `refresh_twap` returns in the current block, without recording the
+/// price. The observation it would record carries no weight yet.
+pub fn peek_twap(
+    deps: Deps,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    let spot = query_price(deps, env, collateral, collateral_type)?;
+    let (_, twap) = with_twap(deps.storage, env, collateral, spot)?;
+    Ok(twap)
+}
+
+/// Refreshes the price and returns the higher of spot and TWAP, which
+/// redemptions pay out collateral at.
+pub fn refresh_max_price(
+    mut deps: DepsMut,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    let spot = refresh_price(deps.branch(), env, collateral, collateral_type)?;
+    let (spot, twap) = with_twap(deps.storage, env, collateral, spot)?;
+    Ok(spot.max(twap))
+}
+
+/// What `refresh_min_price` returns in the current block, without recording
+/// the price.
+pub fn peek_min_price(
+    deps: Deps,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    let spot = query_price(deps, env, collateral, collateral_type)?;
+    let (spot, twap) = with_twap(deps.storage, env, collateral, spot)?;
+    Ok(spot.min(twap))
+}
+
+/// Refreshes the price and returns the lower of spot and TWAP, which borrowing
+/// and withdrawals are checked against.
+pub fn refresh_min_price(
+    mut deps: DepsMut,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    let spot = refresh_price(deps.branch(), env, collateral, collateral_type)?;
+    let (spot, twap) = with_twap(deps.storage, env, collateral, spot)?;
+    Ok(spot.min(twap))
+}
+
+/// TWAP of `collateral` as of the current block without recording anything,
+/// falling back to the spot price before the first observation.
+pub fn query_twap(
+    deps: Deps,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> Result<Uint128, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    match twap_price(deps.storage, env, collateral, config.twap_window)? {
+        Some(twap) => Ok(twap),
+        None => query_price(deps, env, collateral, collateral_type),
+    }
+}
+
+pub fn execute_poke(
+    deps: DepsMut,
+    env: Env,
+    collateral: String,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let price = refresh_price(deps, &env, &collateral, &collateral_type)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "poke")
+        .add_attribute("collateral", collateral)
+        .add_attribute("price", price))
+}
+
+fn source_price_response(
+    deps: Deps,
+    env: &Env,
+    config: &Config,
+    adapter: OracleAdapter,
+) -> SourcePriceResponse {
+    match source_price(deps, env, config, &adapter) {
+        Ok(price) => SourcePriceResponse {
+            adapter,
+            price: Some(price),
+            error: None,
+        },
+        Err(err) => SourcePriceResponse {
+            adapter,
+            price: None,
+            error: Some(err.to_string()),
+        },
+    }
+}
+
+pub fn query_oracle_prices(
+    deps: Deps,
+    env: Env,
+    collateral: String,
+) -> StdResult<OraclePricesResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let collateral_type = COLLATERALTYPES.load(deps.storage, collateral.clone())?;
+    let oracle_config = load_oracle_config(deps, &config, &collateral, &collateral_type)?;
+
+    let sources = oracle_config
+        .sources
+        .into_iter()
+        .map(|source| source_price_response(deps, &env, &config, source.adapter))
+        .collect();
+    let fallback = oracle_config
+        .fallback
+        .map(|adapter| source_price_response(deps, &env, &config, adapter));
+    let (aggregate, error) = match query_price(deps, &env, &collateral, &collateral_type) {
+        Ok(price) => (Some(price), None),
+        Err(err) => (None, Some(err.to_string())),
+    };
+    let twap = twap_price(deps.storage, &env, &collateral, config.twap_window)?;
+
+    Ok(OraclePricesResponse {
+        collateral,
+        sources,
+        fallback,
+        aggregate,
+        error,
+        twap,
+    })
+}
+
+/// Checks a source configuration before it is registered.
+pub fn validate_oracle_config(
+    deps: Deps,
+    oracle_config: &OracleConfig,
+) -> Result<(), ContractError> {
+    if oracle_config.sources.is_empty() {
+        return Err(ContractError::InvalidOracleConfig {
+            reason: "at least one source is required".to_string(),
+        });
+    }
+    if oracle_config.quorum == 0 || oracle_config.quorum as usize > oracle_config.sources.len() {
+        return Err(ContractError::InvalidOracleConfig {
+            reason: "quorum must be between 1 and the number of sources".to_string(),
+        });
+    }
+    if oracle_config.aggregation == Aggregation::Weighted
+        && oracle_config
+            .sources
+            .iter()
+            .any(|source| source.weight == 0)
+    {
+        return Err(ContractError::InvalidOracleConfig {
+            reason: "weighted sources need a positive weight".to_string(),
+        });
+    }
+
+    let adapters = oracle_config
+        .sources
+        .iter()
+        .map(|source| &source.adapter)
+        .chain(oracle_config.fallback.iter());
+    for adapter in adapters {
+        let contract = match adapter {
+            OracleAdapter::Hongbai { contract, .. } => contract,
+            OracleAdapter::Generic { contract, .. } => contract,
+        };
+        deps.api.addr_validate(contract.as_str())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,114 @@
+use cosmwasm_std::{Deps, DepsMut, MessageInfo, Order, Response, StdResult, Storage};
+
+use crate::access::ensure_role;
+use crate::contract::load_collateral_type;
+use crate::error::ContractError;
+use crate::msg::{CollateralPausedResponse, PauseStateResponse};
+use crate::state::{PauseOp, Role, COLLATERALPAUSED, PAUSED};
+
+fn bitmap(ops: &[PauseOp]) -> u8 {
+    ops.iter().fold(0, |bits, op| bits | op.bit())
+}
+
+fn ops(bits: u8) -> Vec<PauseOp> {
+    PauseOp::ALL
+        .iter()
+        .copied()
+        .filter(|op| bits & op.bit() != 0)
+        .collect()
+}
+
+/// Fails when `op` is paused globally or for `collateral`.
+pub fn ensure_not_paused(
+    storage: &dyn Storage,
+    op: PauseOp,
+    collateral: Option<&str>,
+) -> Result<(), ContractError> {
+    let mut paused = PAUSED.may_load(storage)?.unwrap_or_default();
+    if let Some(collateral) = collateral {
+        paused |= COLLATERALPAUSED
+            .may_load(storage, collateral.to_string())?
+            .unwrap_or_default();
+    }
+    if paused & op.bit() != 0 {
+        return Err(ContractError::Paused { op });
+    }
+    Ok(())
+}
+
+/// Sets (`paused`) or clears the bits of `ops` in the global bitmap or the
+/// bitmap of `collateral`.
+fn update_paused(
+    storage: &mut dyn Storage,
+    collateral: &Option<String>,
+    ops: &[PauseOp],
+    paused: bool,
+) -> Result<(), ContractError> {
+    let update = |bits: u8| {
+        if paused {
+            bits | bitmap(ops)
+        } else {
+            bits & !bitmap(ops)
+        }
+    };
+    match collateral {
+        Some(collateral) => {
+            load_collateral_type(storage, collateral)?;
+            let bits = COLLATERALPAUSED
+                .may_load(storage, collateral.clone())?
+                .unwrap_or_default();
+            COLLATERALPAUSED.save(storage, collateral.clone(), &update(bits))?;
+        }
+        None => {
+            let bits = PAUSED.may_load(storage)?.unwrap_or_default();
+            PAUSED.save(storage, &update(bits))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn execute_pause(
+    deps: DepsMut,
+    info: MessageInfo,
+    collateral: Option<String>,
+    ops: Vec<PauseOp>,
+) -> Result<Response, ContractError> {
+    ensure_role(deps.storage, &info.sender, Role::Guardian)?;
+    update_paused(deps.storage, &collateral, &ops, true)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "pause")
+        .add_attribute("collateral", collateral.unwrap_or_default())
+        .add_attribute("ops", bitmap(&ops).to_string()))
+}
+
+pub fn execute_unpause(
+    deps: DepsMut,
+    info: MessageInfo,
+    collateral: Option<String>,
+    ops: Vec<PauseOp>,
+) -> Result<Response, ContractError> {
+    ensure_role(deps.storage, &info.sender, Role::Admin)?;
+    update_paused(deps.storage, &collateral, &ops, false)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "unpause")
+        .add_attribute("collateral", collateral.unwrap_or_default())
+        .add_attribute("ops", bitmap(&ops).to_string()))
+}
+
+pub fn query_pause_state(deps: Deps) -> StdResult<PauseStateResponse> {
+    let global = ops(PAUSED.may_load(deps.storage)?.unwrap_or_default());
+    let collateral = COLLATERALPAUSED
+        .range(deps.storage, None, None, Order::Ascending)
+        .filter(|item| !matches!(item, Ok((_, 0))))
+        .map(|item| {
+            let (collateral, bits) = item?;
+            Ok(CollateralPausedResponse {
+                collateral,
+                ops: ops(bits),
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(PauseStateResponse { global, collateral })
+}
@@ -0,0 +1,176 @@
+use cosmwasm_std::{
+    to_json_binary, Decimal, Deps, DepsMut, Env, MessageInfo, Order, Response, StdResult, Uint128,
+    WasmMsg,
+};
+use cw20::{Cw20ExecuteMsg, Denom};
+
+use crate::access::ensure_role;
+use crate::contract::{
+    amount_sent, burn_stable, check_global_debt_ceiling, mint_stable, send_asset,
+};
+use crate::error::ContractError;
+use crate::msg::{PsmAssetResponse, PsmAssetsResponse};
+use crate::pause::ensure_not_paused;
+use crate::settlement::ensure_live;
+use crate::state::{PauseOp, PsmAsset, Role, PSMASSETS, PSMRESERVES, STABLE};
+use crate::surplus::book_surplus;
+
+// Whitelisted assets are swapped 1:1 in base units, so they are expected to use
+// the same number of decimals as mUSD.
+
+pub fn execute_set_psm_asset(
+    deps: DepsMut,
+    info: MessageInfo,
+    asset: String,
+    psm_asset: PsmAsset,
+) -> Result<Response, ContractError> {
+    ensure_role(deps.storage, &info.sender, Role::RiskManager)?;
+    if psm_asset.tin >= Decimal::one() || psm_asset.tout >= Decimal::one() {
+        return Err(ContractError::InvalidPsmAsset {
+            reason: "tin and tout must be below 1".to_string(),
+        });
+    }
+    if let Denom::Cw20(token) = &psm_asset.denom {
+        deps.api.addr_validate(token.as_str())?;
+    }
+
+    PSMASSETS.save(deps.storage, asset.clone(), &psm_asset)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_psm_asset")
+        .add_attribute("asset", asset))
+}
+
+/// Takes `amount` of a whitelisted asset into the reserve and mints mUSD 1:1 minus `tin`.
+pub fn execute_psm_sell(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    asset: String,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    ensure_not_paused(deps.storage, PauseOp::Swap, None)?;
+    let psm_asset = load_psm_asset(deps.as_ref(), &asset)?;
+    if amount.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+
+    let reserve = PSMRESERVES
+        .may_load(deps.storage, asset.clone())?
+        .unwrap_or_default();
+    let new_reserve = reserve + amount;
+    if new_reserve > psm_asset.debt_ceiling {
+        return Err(ContractError::PsmCeilingExceeded { asset });
+    }
+
+    let fee = amount * psm_asset.tin;
+    let minted = amount - fee;
+
+    let mut response = Response::new();
+    match &psm_asset.denom {
+        Denom::Native(denom) => {
+            if amount_sent(info.funds.clone(), denom.clone()) != amount {
+                return Err(ContractError::TransferFailed {});
+            }
+        }
+        Denom::Cw20(token) => {
+            response = response.add_message(WasmMsg::Execute {
+                contract_addr: token.to_string(),
+                msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
+                    owner: info.sender.to_string(),
+                    recipient: env.contract.address.to_string(),
+                    amount,
+                })?,
+                funds: vec![],
+            });
+        }
+    }
+
+    PSMRESERVES.save(deps.storage, asset.clone(), &new_reserve)?;
+    check_global_debt_ceiling(deps.storage, &env)?;
+    if !fee.is_zero() {
+        book_surplus(deps.storage, fee)?;
+    }
+
+    let token = STABLE.load(deps.storage)?;
+    Ok(response
+        .add_message(mint_stable(info.sender, minted, token))
+        .add_attribute("action", "psm_sell")
+        .add_attribute("asset", asset)
+        .add_attribute("amount", amount)
+        .add_attribute("fee", fee))
+}
+
+/// Pays out `amount` of a whitelisted asset from the reserve against mUSD 1:1 plus `tout`.
+pub fn execute_psm_buy(
+    deps: DepsMut,
+    info: MessageInfo,
+    asset: String,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    ensure_not_paused(deps.storage, PauseOp::Swap, None)?;
+    let psm_asset = load_psm_asset(deps.as_ref(), &asset)?;
+    if amount.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+
+    let reserve = PSMRESERVES
+        .may_load(deps.storage, asset.clone())?
+        .unwrap_or_default();
+    let new_reserve =
+        reserve
+            .checked_sub(amount)
+            .map_err(|_| ContractError::PsmReserveInsufficient {
+                asset: asset.clone(),
+            })?;
+
+    let fee = amount * psm_asset.tout;
+
+    PSMRESERVES.save(deps.storage, asset.clone(), &new_reserve)?;
+    if !fee.is_zero() {
+        book_surplus(deps.storage, fee)?;
+    }
+
+    let token = STABLE.load(deps.storage)?;
+    Ok(Response::new()
+        .add_message(burn_stable(info.sender.clone(), amount + fee, token))
+        .add_message(send_asset(&psm_asset.denom, info.sender, amount)?)
+        .add_attribute("action", "psm_buy")
+        .add_attribute("asset", asset)
+        .add_attribute("amount", amount)
+        .add_attribute("fee", fee))
+}
+
+fn load_psm_asset(deps: Deps, asset: &str) -> Result<PsmAsset, ContractError> {
+    PSMASSETS
+        .may_load(deps.storage, asset.to_string())?
+        .ok_or_else(|| ContractError::UnknownPsmAsset {
+            asset: asset.to_string(),
+        })
+}
+
+pub fn query_psm_asset(deps: Deps, asset: String) -> StdResult<PsmAssetResponse> {
+    let psm_asset = PSMASSETS.load(deps.storage, asset.clone())?;
+    let reserve = PSMRESERVES
+        .may_load(deps.storage, asset.clone())?
+        .unwrap_or_default();
+    Ok(PsmAssetResponse {
+        asset,
+        available: psm_asset.debt_ceiling.saturating_sub(reserve),
+        reserve,
+        psm_asset,
+    })
+}
+
+pub fn query_psm_assets(deps: Deps) -> StdResult<PsmAssetsResponse> {
+    let assets = PSMASSETS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<String>>>()?;
+    let assets = assets
+        .into_iter()
+        .map(|asset| query_psm_asset(deps, asset))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(PsmAssetsResponse { assets })
+}
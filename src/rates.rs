@@ -0,0 +1,108 @@
+use cosmwasm_std::{Decimal, Env, Order, StdResult, Storage, Uint128};
+
+use crate::state::{
+    CollateralRate, CollateralType, COLLATERALTYPES, PSMRESERVES, RATES, SHUTDOWN, TOTALDEBT,
+};
+use crate::surplus::book_surplus;
+
+pub const SECONDS_PER_YEAR: u64 = 31_536_000;
+
+/// Returns the cumulative rate of `collateral` as of the current block without
+/// persisting it, for use in queries.
+pub fn current_rate(
+    storage: &dyn Storage,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> StdResult<CollateralRate> {
+    // the rate stops growing once global settlement has started
+    let now = match SHUTDOWN.may_load(storage)? {
+        Some(shutdown) => env.block.time.seconds().min(shutdown.started_at),
+        None => env.block.time.seconds(),
+    };
+    let rate = RATES
+        .may_load(storage, collateral.to_string())?
+        .unwrap_or(CollateralRate {
+            rate: Decimal::one(),
+            last_updated: now,
+        });
+    if now <= rate.last_updated || collateral_type.stability_fee.is_zero() {
+        return Ok(CollateralRate {
+            rate: rate.rate,
+            last_updated: now,
+        });
+    }
+
+    let elapsed = Decimal::from_ratio(now - rate.last_updated, SECONDS_PER_YEAR);
+    let growth = Decimal::one() + collateral_type.stability_fee * elapsed;
+    Ok(CollateralRate {
+        rate: rate.rate * growth,
+        last_updated: now,
+    })
+}
+
+/// Brings the cumulative rate of `collateral` up to the current block and books
+/// the interest accrued on its outstanding debt as protocol surplus.
+pub fn accrue(
+    storage: &mut dyn Storage,
+    env: &Env,
+    collateral: &str,
+    collateral_type: &CollateralType,
+) -> StdResult<Decimal> {
+    let previous = RATES
+        .may_load(storage, collateral.to_string())?
+        .map(|rate| rate.rate)
+        .unwrap_or_else(Decimal::one);
+    let updated = current_rate(storage, env, collateral, collateral_type)?;
+
+    let total_normalized = TOTALDEBT
+        .may_load(storage, collateral.to_string())?
+        .unwrap_or_default();
+    let fees = denormalize(total_normalized, updated.rate)
+        .checked_sub(denormalize(total_normalized, previous))?;
+    if !fees.is_zero() {
+        book_surplus(storage, fees)?;
+    }
+
+    RATES.save(storage, collateral.to_string(), &updated)?;
+    Ok(updated.rate)
+}
+
+/// mUSD outstanding across all collateral types as of the current block, plus
+/// the mUSD minted against PSM reserves.
+pub fn global_debt(storage: &dyn Storage, env: &Env) -> StdResult<Uint128> {
+    let mut total = Uint128::zero();
+    let collateral_types = COLLATERALTYPES
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (collateral, collateral_type) in collateral_types {
+        let rate = current_rate(storage, env, &collateral, &collateral_type)?.rate;
+        let normalized = TOTALDEBT.may_load(storage, collateral)?.unwrap_or_default();
+        total += denormalize(normalized, rate);
+    }
+    for reserve in PSMRESERVES.range(storage, None, None, Order::Ascending) {
+        total += reserve?.1;
+    }
+    Ok(total)
+}
+
+/// Converts an mUSD amount into normalized debt, rounding down.
+pub fn normalize(amount: Uint128, rate: Decimal) -> Uint128 {
+    amount.multiply_ratio(Decimal::one().atomics(), rate.atomics())
+}
+
+/// Converts an mUSD amount into normalized debt, rounding up so the resulting
+/// debt is never less than `amount`.
+pub fn normalize_ceil(amount: Uint128, rate: Decimal) -> Uint128 {
+    let normalized = normalize(amount, rate);
+    if denormalize(normalized, rate) < amount {
+        normalized + Uint128::new(1)
+    } else {
+        normalized
+    }
+}
+
+/// Converts normalized debt into the mUSD currently owed.
+pub fn denormalize(normalized: Uint128, rate: Decimal) -> Uint128 {
+    normalized * rate
+}
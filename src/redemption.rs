@@ -0,0 +1,206 @@
+use cosmwasm_std::{
+    Decimal, Deps, DepsMut, Env, Event, MessageInfo, Order, Response, StdResult, Storage, Uint128,
+};
+use cw20::{Cw20QueryMsg, TokenInfoResponse};
+
+use crate::contract::{
+    burn_stable, calculate_collateral_usd, calculate_health_factor, calculate_usd_in_collateral,
+    load_collateral_type, repay_debt, save_position, send_asset,
+};
+use crate::error::ContractError;
+use crate::msg::RedemptionRateResponse;
+use crate::oracle::refresh_max_price;
+use crate::pause::ensure_not_paused;
+use crate::rates::{accrue, denormalize};
+use crate::settlement::ensure_live;
+use crate::state::{positions, PauseOp, Position, RedemptionRate, CONFIG, REDEMPTIONRATE, STABLE};
+
+// The fee follows Liquity: a floor plus a base rate that grows with every
+// redemption by half the share of the supply redeemed, and halves every 12 hours.
+
+/// Per-minute decay of the base rate, 0.5^(1/720) with 18 decimals.
+const MINUTE_DECAY_FACTOR: u128 = 999_037_758_833_783_000;
+/// Most vaults a single redemption looks at, unsafe ones included.
+const MAX_REDEMPTION_VAULTS: usize = 30;
+
+fn fee_floor() -> Decimal {
+    Decimal::permille(5)
+}
+
+/// Burns up to `amount` mUSD for collateral taken from the vaults of
+/// `collateral` with the lowest collateral ratio first, at face value minus the
+/// redemption fee. Each vault hands over collateral worth the debt it is
+/// relieved of, so the system stays as collateralized as before. Vaults below
+/// the minimum health factor are left to liquidations.
+pub fn execute_redeem(
+    mut deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    collateral: String,
+    amount: Uint128,
+    max_fee_rate: Decimal,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    ensure_not_paused(deps.storage, PauseOp::Swap, Some(&collateral))?;
+    if amount.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let rate = accrue(deps.storage, &env, &collateral, &collateral_type)?;
+    // the higher of spot and TWAP, so a dip of the feed cannot be used to draw
+    // more collateral
+    let price = refresh_max_price(deps.branch(), &env, &collateral, &collateral_type)?;
+
+    let mut remaining = amount;
+    let mut targets = vec![];
+    for item in positions()
+        .idx
+        .ratio
+        .sub_prefix(collateral.clone())
+        .range(deps.storage, None, None, Order::Ascending)
+        .take(MAX_REDEMPTION_VAULTS)
+    {
+        let (vault_id, position): (u64, Position) = item?;
+        if position.debt.is_zero() {
+            break;
+        }
+        let debt = denormalize(position.debt, rate);
+        let health_factor = calculate_health_factor(
+            calculate_collateral_usd(position.collateral, price),
+            debt,
+            collateral_type.liquidity_threashold,
+        );
+        if health_factor < collateral_type.min_threashold {
+            continue;
+        }
+        let take = remaining.min(debt);
+        remaining -= take;
+        targets.push((vault_id, position, take));
+        if remaining.is_zero() {
+            break;
+        }
+    }
+
+    let mut redeemed = Uint128::zero();
+    let mut drawn = Uint128::zero();
+    let mut events = vec![];
+    for (vault_id, mut position, take) in targets {
+        let (debt, repaid) = repay_debt(position.debt, take, rate);
+        let taken = calculate_usd_in_collateral(repaid, price).min(position.collateral);
+        position.debt = debt;
+        position.collateral -= taken;
+        save_position(deps.storage, vault_id, &position)?;
+        redeemed += repaid;
+        drawn += taken;
+        events.push(
+            Event::new("redemption")
+                .add_attribute("vault_id", vault_id.to_string())
+                .add_attribute("owner", position.owner)
+                .add_attribute("debt", repaid)
+                .add_attribute("collateral", taken),
+        );
+    }
+    if redeemed.is_zero() {
+        return Err(ContractError::NothingToRedeem { collateral });
+    }
+
+    let token = STABLE.load(deps.storage)?;
+    let token_info: TokenInfoResponse = deps
+        .querier
+        .query_wasm_smart(token.clone(), &Cw20QueryMsg::TokenInfo {})?;
+    let fee_rate = bump_base_rate(deps.storage, &env, redeemed, token_info.total_supply)?;
+    if fee_rate > max_fee_rate {
+        return Err(ContractError::RedemptionFeeTooHigh { fee_rate });
+    }
+    let fee = drawn * fee_rate;
+
+    let config = CONFIG.load(deps.storage)?;
+    let mut response = Response::new()
+        .add_message(burn_stable(info.sender.clone(), redeemed, token))
+        .add_attribute("action", "redeem")
+        .add_attribute("collateral", collateral)
+        .add_attribute("redeemed", redeemed)
+        .add_attribute("drawn", drawn)
+        .add_attribute("fee", fee)
+        .add_attribute("fee_rate", fee_rate.to_string())
+        .add_events(events);
+    if !(drawn - fee).is_zero() {
+        response = response.add_message(send_asset(
+            &collateral_type.denom,
+            info.sender,
+            drawn - fee,
+        )?);
+    }
+    if !fee.is_zero() {
+        response = response.add_message(send_asset(&collateral_type.denom, config.treasury, fee)?);
+    }
+    Ok(response)
+}
+
+/// Decays the base rate to the current block, raises it by half the share of
+/// the supply that `redeemed` is and returns the resulting fee rate.
+fn bump_base_rate(
+    storage: &mut dyn Storage,
+    env: &Env,
+    redeemed: Uint128,
+    supply: Uint128,
+) -> StdResult<Decimal> {
+    let mut redemption_rate = decayed_rate(storage, env)?;
+    let increase = if supply.is_zero() {
+        Decimal::one()
+    } else {
+        Decimal::from_ratio(redeemed, supply * Uint128::new(2))
+    };
+    redemption_rate.base_rate = (redemption_rate.base_rate + increase).min(Decimal::one());
+    REDEMPTIONRATE.save(storage, &redemption_rate)?;
+    Ok(fee_rate(redemption_rate.base_rate))
+}
+
+fn fee_rate(base_rate: Decimal) -> Decimal {
+    (fee_floor() + base_rate).min(Decimal::one())
+}
+
+/// Base rate decayed over the whole minutes passed since its last update.
+fn decayed_rate(storage: &dyn Storage, env: &Env) -> StdResult<RedemptionRate> {
+    let now = env.block.time.seconds();
+    let redemption_rate = match REDEMPTIONRATE.may_load(storage)? {
+        Some(redemption_rate) => redemption_rate,
+        None => {
+            return Ok(RedemptionRate {
+                base_rate: Decimal::zero(),
+                updated_at: now,
+            })
+        }
+    };
+    let minutes = now.saturating_sub(redemption_rate.updated_at) / 60;
+    Ok(RedemptionRate {
+        base_rate: redemption_rate.base_rate * decay(minutes),
+        updated_at: redemption_rate.updated_at + minutes * 60,
+    })
+}
+
+/// `MINUTE_DECAY_FACTOR` to the power of `minutes`, by repeated squaring. Past
+/// a thousand years the factor is zero anyway.
+fn decay(minutes: u64) -> Decimal {
+    let mut exponent = minutes.min(525_600_000);
+    let mut factor = Decimal::raw(MINUTE_DECAY_FACTOR);
+    let mut result = Decimal::one();
+    while exponent > 0 {
+        if exponent & 1 == 1 {
+            result = result * factor;
+        }
+        factor = factor * factor;
+        exponent >>= 1;
+    }
+    result
+}
+
+/// Base rate and fee rate a redemption would start from in the current block.
+/// The redemption itself raises the base rate before its fee is charged.
+pub fn query_redemption_rate(deps: Deps, env: Env) -> StdResult<RedemptionRateResponse> {
+    let base_rate = decayed_rate(deps.storage, &env)?.base_rate;
+    Ok(RedemptionRateResponse {
+        base_rate,
+        fee_rate: fee_rate(base_rate),
+    })
+}
@@ -0,0 +1,160 @@
+use cosmwasm_std::{
+    Decimal, Deps, DepsMut, Env, MessageInfo, Response, StdResult, Storage, Uint128,
+};
+
+use crate::contract::{burn_stable, mint_stable};
+use crate::error::ContractError;
+use crate::msg::SavingsBalanceResponse;
+use crate::rates::{denormalize, normalize, normalize_ceil, SECONDS_PER_YEAR};
+use crate::settlement::ensure_live;
+use crate::state::{Savings, SAVINGS, SAVINGSDEPOSITS, SHUTDOWN, STABLE};
+use crate::surplus::book_bad_debt;
+
+// Savings are stored normalized against the chi index, which grows at the
+// annual `savings_rate` like the rate index of a collateral type. The interest
+// is paid out of the protocol surplus; whatever the surplus cannot cover is
+// booked as bad debt, so the rate should stay below what stability fees earn.
+
+fn load_savings(storage: &dyn Storage) -> StdResult<Savings> {
+    Ok(SAVINGS.may_load(storage)?.unwrap_or_else(|| Savings {
+        rate: Decimal::zero(),
+        chi: Decimal::one(),
+        updated_at: 0,
+        total: Uint128::zero(),
+    }))
+}
+
+/// Savings state with chi as of the current block, without persisting it.
+/// Chi stops growing once global settlement has started.
+pub fn current_savings(storage: &dyn Storage, env: &Env) -> StdResult<Savings> {
+    let mut savings = load_savings(storage)?;
+    let now = env.block.time.seconds();
+    if now <= savings.updated_at || SHUTDOWN.may_load(storage)?.is_some() {
+        return Ok(savings);
+    }
+    if !savings.rate.is_zero() {
+        let elapsed = Decimal::from_ratio(now - savings.updated_at, SECONDS_PER_YEAR);
+        savings.chi = savings.chi * (Decimal::one() + savings.rate * elapsed);
+    }
+    savings.updated_at = now;
+    Ok(savings)
+}
+
+/// Interest the savings have earned since the last update, not yet booked.
+pub fn pending_interest(storage: &dyn Storage, env: &Env) -> StdResult<Uint128> {
+    let previous = load_savings(storage)?.chi;
+    let savings = current_savings(storage, env)?;
+    let earned = denormalize(savings.total, savings.chi);
+    Ok(earned.saturating_sub(denormalize(savings.total, previous)))
+}
+
+/// Brings chi up to the current block and books the interest earned by all
+/// savings since the last update against the surplus. Interest the surplus
+/// cannot cover is not capped: it becomes bad debt, reported by
+/// `SystemBalance`, and is left to governance to lower the rate.
+pub fn drip(storage: &mut dyn Storage, env: &Env) -> StdResult<Savings> {
+    let interest = pending_interest(storage, env)?;
+    if !interest.is_zero() {
+        book_bad_debt(storage, interest)?;
+    }
+    let savings = current_savings(storage, env)?;
+    SAVINGS.save(storage, &savings)?;
+    Ok(savings)
+}
+
+/// Sets the annual savings rate, after paying the interest earned at the old one.
+pub fn set_savings_rate(storage: &mut dyn Storage, env: &Env, rate: Decimal) -> StdResult<()> {
+    let mut savings = drip(storage, env)?;
+    savings.rate = rate;
+    SAVINGS.save(storage, &savings)
+}
+
+/// Burns `amount` mUSD from the sender and credits it to its savings. Needs an
+/// mUSD allowance.
+pub fn execute_join(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    let mut savings = drip(deps.storage, &env)?;
+    // round down so the savings never exceed the mUSD burned
+    let normalized = normalize(amount, savings.chi);
+    if normalized.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+    SAVINGSDEPOSITS.update(
+        deps.storage,
+        info.sender.clone(),
+        |deposit| -> StdResult<_> { Ok(deposit.unwrap_or_default() + normalized) },
+    )?;
+    savings.total += normalized;
+    SAVINGS.save(deps.storage, &savings)?;
+
+    let token = STABLE.load(deps.storage)?;
+    Ok(Response::new()
+        .add_message(burn_stable(info.sender.clone(), amount, token))
+        .add_attribute("action", "join")
+        .add_attribute("owner", info.sender)
+        .add_attribute("amount", amount)
+        .add_attribute("chi", savings.chi.to_string()))
+}
+
+/// Mints up to `amount` mUSD of the sender's savings, interest included, back
+/// to it.
+pub fn execute_exit(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let mut savings = drip(deps.storage, &env)?;
+    let deposit = SAVINGSDEPOSITS
+        .may_load(deps.storage, info.sender.clone())?
+        .unwrap_or_default();
+    let balance = denormalize(deposit, savings.chi);
+    let withdrawn = amount.min(balance);
+    if withdrawn.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+    // round up so the savings left never exceed what was not withdrawn
+    let normalized = if withdrawn == balance {
+        deposit
+    } else {
+        normalize_ceil(withdrawn, savings.chi).min(deposit)
+    };
+    if normalized == deposit {
+        SAVINGSDEPOSITS.remove(deps.storage, info.sender.clone());
+    } else {
+        SAVINGSDEPOSITS.save(deps.storage, info.sender.clone(), &(deposit - normalized))?;
+    }
+    savings.total -= normalized;
+    SAVINGS.save(deps.storage, &savings)?;
+
+    let token = STABLE.load(deps.storage)?;
+    Ok(Response::new()
+        .add_message(mint_stable(info.sender.clone(), withdrawn, token))
+        .add_attribute("action", "exit")
+        .add_attribute("owner", info.sender)
+        .add_attribute("amount", withdrawn)
+        .add_attribute("chi", savings.chi.to_string()))
+}
+
+/// mUSD an address holds in savings as of the current block.
+pub fn query_savings_balance(
+    deps: Deps,
+    env: Env,
+    address: String,
+) -> StdResult<SavingsBalanceResponse> {
+    let address = deps.api.addr_validate(&address)?;
+    let savings = current_savings(deps.storage, &env)?;
+    let deposit = SAVINGSDEPOSITS
+        .may_load(deps.storage, address)?
+        .unwrap_or_default();
+    Ok(SavingsBalanceResponse {
+        balance: denormalize(deposit, savings.chi),
+        rate: savings.rate,
+        chi: savings.chi,
+    })
+}
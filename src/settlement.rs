@@ -0,0 +1,323 @@
+use cosmwasm_std::{
+    Addr, Deps, DepsMut, Env, MessageInfo, Order, Response, StdResult, Storage, Uint128,
+};
+use cw20::{Cw20QueryMsg, TokenInfoResponse};
+
+use crate::access::ensure_role;
+use crate::contract::{
+    burn_stable, load_collateral_type, load_owned_position, load_position, save_position,
+    send_asset,
+};
+use crate::error::ContractError;
+use crate::msg::{SettledCollateralResponse, SettlementResponse};
+use crate::oracle::query_price;
+use crate::rates::{accrue, denormalize};
+use crate::savings::{current_savings, drip};
+use crate::state::{
+    Position, Role, Shutdown, COLLATERALTYPES, CONFIG, LASTPRICES, LOTS, PSMASSETS, PSMRESERVES,
+    RATES, SETTLEDCOLLATERAL, SETTLEMENTPRICES, SHUTDOWN, STABLE, TOTALDEBT,
+};
+use crate::surplus::book_bad_debt;
+
+/// Fails once global settlement has started.
+pub fn ensure_live(storage: &dyn Storage) -> Result<(), ContractError> {
+    if SHUTDOWN.may_load(storage)?.is_some() {
+        return Err(ContractError::SystemShutdown {});
+    }
+    Ok(())
+}
+
+fn load_shutdown(storage: &dyn Storage) -> Result<Shutdown, ContractError> {
+    SHUTDOWN
+        .may_load(storage)?
+        .ok_or(ContractError::NotShutdown {})
+}
+
+/// Starts global settlement: stops fee accrual and the savings rate, and
+/// freezes the price of every collateral type at its last accepted price, or
+/// at the current oracle price when none was accepted yet.
+pub fn execute_shutdown(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    ensure_role(deps.storage, &info.sender, Role::Admin)?;
+    ensure_live(deps.storage)?;
+
+    let collateral_types = COLLATERALTYPES
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (collateral, collateral_type) in collateral_types {
+        accrue(deps.storage, &env, &collateral, &collateral_type)?;
+        let price = match LASTPRICES.may_load(deps.storage, collateral.clone())? {
+            Some(last) => last.price,
+            None => query_price(deps.as_ref(), &env, &collateral, &collateral_type)?,
+        };
+        SETTLEMENTPRICES.save(deps.storage, collateral, &price)?;
+    }
+    drip(deps.storage, &env)?;
+
+    let shutdown = Shutdown {
+        started_at: env.block.time.seconds(),
+        redeemable_at: env.block.time.seconds() + config.settlement_delay,
+        unredeemed: None,
+    };
+    SHUTDOWN.save(deps.storage, &shutdown)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "shutdown")
+        .add_attribute("redeemable_at", shutdown.redeemable_at.to_string()))
+}
+
+/// Cancels `debt` against up to `available` of `collateral` at the frozen
+/// price. The collateral covering the debt moves to the settlement pool; debt
+/// it cannot cover is recorded as bad debt. Returns the collateral taken.
+fn settle_debt(
+    storage: &mut dyn Storage,
+    collateral: &str,
+    debt: Uint128,
+    available: Uint128,
+) -> Result<Uint128, ContractError> {
+    let price = SETTLEMENTPRICES.load(storage, collateral.to_string())?;
+    // round up so the pool never holds less than the debt it backs
+    let owed = (debt * Uint128::new(1_000_000) + price - Uint128::new(1)) / price;
+    let seized = owed.min(available);
+    if seized < owed {
+        let covered = seized.multiply_ratio(price, 1_000_000u128);
+        book_bad_debt(storage, debt - covered)?;
+    }
+    SETTLEDCOLLATERAL.update(storage, collateral.to_string(), |pool| -> StdResult<_> {
+        Ok(pool.unwrap_or_default() + seized)
+    })?;
+    Ok(seized)
+}
+
+/// Settles the debt of a vault against its collateral. Returns the collateral
+/// taken.
+fn settle_position(
+    storage: &mut dyn Storage,
+    vault_id: u64,
+    position: &mut Position,
+) -> Result<Uint128, ContractError> {
+    if position.debt.is_zero() {
+        return Ok(Uint128::zero());
+    }
+    let rate = RATES.load(storage, position.collateral_type.clone())?.rate;
+    let debt = denormalize(position.debt, rate);
+    let seized = settle_debt(
+        storage,
+        &position.collateral_type,
+        debt,
+        position.collateral,
+    )?;
+
+    position.debt = Uint128::zero();
+    position.collateral -= seized;
+    save_position(storage, vault_id, position)?;
+    Ok(seized)
+}
+
+/// Settles a vault. Open to anyone.
+pub fn execute_settle_vault(deps: DepsMut, vault_id: u64) -> Result<Response, ContractError> {
+    load_shutdown(deps.storage)?;
+    let mut position = load_position(deps.storage, vault_id)?;
+    let seized = settle_position(deps.storage, vault_id, &mut position)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "settle_vault")
+        .add_attribute("vault_id", vault_id.to_string())
+        .add_attribute("collateral", position.collateral_type)
+        .add_attribute("seized", seized))
+}
+
+/// Settles the debt of an auction lot left open by the shutdown against its
+/// collateral, without the penalty, and hands the rest of the collateral back
+/// to the vault owner. Open to anyone.
+pub fn execute_settle_lot(deps: DepsMut, lot_id: u64) -> Result<Response, ContractError> {
+    load_shutdown(deps.storage)?;
+    let lot = LOTS
+        .may_load(deps.storage, lot_id)?
+        .ok_or(ContractError::UnknownLot { lot_id })?;
+    let seized = settle_debt(deps.storage, &lot.collateral, lot.debt, lot.amount)?;
+    LOTS.remove(deps.storage, lot_id);
+
+    let mut response = Response::new()
+        .add_attribute("action", "settle_lot")
+        .add_attribute("lot_id", lot_id.to_string())
+        .add_attribute("collateral", lot.collateral.clone())
+        .add_attribute("seized", seized);
+    let returned = lot.amount - seized;
+    if !returned.is_zero() {
+        let collateral_type = load_collateral_type(deps.storage, &lot.collateral)?;
+        response = response
+            .add_message(send_asset(&collateral_type.denom, lot.owner, returned)?)
+            .add_attribute("returned", returned);
+    }
+    Ok(response)
+}
+
+/// Settles the caller's vault if needed and withdraws the collateral left in it.
+pub fn execute_withdraw_excess_collateral(
+    deps: DepsMut,
+    info: MessageInfo,
+    vault_id: u64,
+) -> Result<Response, ContractError> {
+    load_shutdown(deps.storage)?;
+    let mut position = load_owned_position(deps.storage, vault_id, &info.sender)?;
+    let collateral = position.collateral_type.clone();
+    let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+    let seized = settle_position(deps.storage, vault_id, &mut position)?;
+
+    let excess = position.collateral;
+    if excess.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+    position.collateral = Uint128::zero();
+    save_position(deps.storage, vault_id, &position)?;
+
+    Ok(Response::new()
+        .add_message(send_asset(
+            &collateral_type.denom,
+            info.sender.clone(),
+            excess,
+        )?)
+        .add_attribute("action", "withdraw_excess_collateral")
+        .add_attribute("collateral", collateral)
+        .add_attribute("seized", seized)
+        .add_attribute("withdrawn", excess))
+}
+
+/// Fixes the redemption rate once `settlement_delay` has passed and every vault
+/// and auction lot is settled: from then on each mUSD, in circulation or in
+/// savings, redeems for the same share of the pools. Open to anyone.
+pub fn execute_fix_rates(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let mut shutdown = load_shutdown(deps.storage)?;
+    if env.block.time.seconds() < shutdown.redeemable_at {
+        return Err(ContractError::SettlementNotReady {
+            redeemable_at: shutdown.redeemable_at,
+        });
+    }
+    if shutdown.unredeemed.is_some() {
+        return Err(ContractError::RatesFixed {});
+    }
+    for item in TOTALDEBT.range(deps.storage, None, None, Order::Ascending) {
+        if !item?.1.is_zero() {
+            return Err(ContractError::UnsettledDebt {});
+        }
+    }
+    if LOTS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_some()
+    {
+        return Err(ContractError::UnsettledDebt {});
+    }
+
+    let token = STABLE.load(deps.storage)?;
+    let token_info: TokenInfoResponse = deps
+        .querier
+        .query_wasm_smart(token, &Cw20QueryMsg::TokenInfo {})?;
+    let savings = current_savings(deps.storage, &env)?;
+    let unredeemed = token_info.total_supply + denormalize(savings.total, savings.chi);
+    shutdown.unredeemed = Some(unredeemed);
+    SHUTDOWN.save(deps.storage, &shutdown)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "fix_rates")
+        .add_attribute("unredeemed", unredeemed))
+}
+
+/// Burns `amount` mUSD from the caller and pays out the same share of every
+/// settlement pool and PSM reserve as `amount` is of the mUSD still to redeem.
+pub fn execute_redeem_settled(
+    deps: DepsMut,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let token = STABLE.load(deps.storage)?;
+    let response = redeem_settled(deps, info.sender.clone(), amount)?.add_message(burn_stable(
+        info.sender,
+        amount,
+        token,
+    ));
+    Ok(response)
+}
+
+/// Pays out the share of the settlement pools and PSM reserves that `amount`
+/// mUSD is of the mUSD still to redeem. Burning the mUSD is left to the caller.
+pub fn redeem_settled(
+    deps: DepsMut,
+    recipient: Addr,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    let mut shutdown = load_shutdown(deps.storage)?;
+    let unredeemed = shutdown.unredeemed.ok_or(ContractError::RatesNotFixed {})?;
+    if amount.is_zero() || amount > unredeemed {
+        return Err(ContractError::InvalidAmount {});
+    }
+    shutdown.unredeemed = Some(unredeemed - amount);
+    SHUTDOWN.save(deps.storage, &shutdown)?;
+
+    let mut response = Response::new()
+        .add_attribute("action", "redeem_settled")
+        .add_attribute("amount", amount);
+
+    let pools = SETTLEDCOLLATERAL
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (collateral, pool) in pools {
+        let share = pool.multiply_ratio(amount, unredeemed);
+        if share.is_zero() {
+            continue;
+        }
+        let collateral_type = load_collateral_type(deps.storage, &collateral)?;
+        SETTLEDCOLLATERAL.save(deps.storage, collateral.clone(), &(pool - share))?;
+        response = response
+            .add_message(send_asset(
+                &collateral_type.denom,
+                recipient.clone(),
+                share,
+            )?)
+            .add_attribute(collateral, share);
+    }
+
+    let reserves = PSMRESERVES
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (asset, reserve) in reserves {
+        let share = reserve.multiply_ratio(amount, unredeemed);
+        if share.is_zero() {
+            continue;
+        }
+        let psm_asset = PSMASSETS.load(deps.storage, asset.clone())?;
+        PSMRESERVES.save(deps.storage, asset.clone(), &(reserve - share))?;
+        response = response
+            .add_message(send_asset(&psm_asset.denom, recipient.clone(), share)?)
+            .add_attribute(asset, share);
+    }
+
+    Ok(response)
+}
+
+pub fn query_settlement(deps: Deps) -> StdResult<SettlementResponse> {
+    let shutdown = SHUTDOWN.may_load(deps.storage)?;
+    let collateral = SETTLEMENTPRICES
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (collateral, price) = item?;
+            let pool = SETTLEDCOLLATERAL
+                .may_load(deps.storage, collateral.clone())?
+                .unwrap_or_default();
+            Ok(SettledCollateralResponse {
+                collateral,
+                price,
+                pool,
+            })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(SettlementResponse {
+        shutdown,
+        collateral,
+    })
+}
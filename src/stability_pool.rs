@@ -0,0 +1,324 @@
+use cosmwasm_std::{
+    to_json_binary, Addr, Deps, DepsMut, Env, MessageInfo, Order, Response, StdResult, Storage,
+    Uint128, WasmMsg,
+};
+use cw20::{Cw20ExecuteMsg, Denom};
+
+use crate::contract::{load_collateral_type, send_asset};
+use crate::error::ContractError;
+use crate::msg::{CollateralGainResponse, PoolDepositResponse, StabilityPoolResponse};
+use crate::settlement::ensure_live;
+use crate::state::{
+    PoolDeposit, StabilityPool, COLLATERALTYPES, POOLCOLLATERAL, POOLDEPOSITS, POOLSNAPSHOTS,
+    POOLSUMS, STABILITYPOOL, STABLE,
+};
+
+// Deposits shrink with every liquidation the pool absorbs and earn the
+// collateral it seizes, both pro rata. Following Liquity, the pool keeps a
+// running product P of the factors deposits shrank by, and per collateral type
+// a running sum S of the collateral earned per unit deposited, weighted by P.
+// A deposit snapshots both, and is worth `amount * P / P_snapshot` with a gain
+// of `amount * (S - S_snapshot) / P_snapshot`. Whenever P would drop below
+// 1e-9 it is scaled up by 1e9 and the scale increases; a liquidation that
+// empties the pool starts a new epoch.
+
+/// Precision of P and S.
+const PRECISION: u128 = 1_000_000_000_000_000_000;
+/// Factor P is scaled up by when it gets too small.
+const SCALE_FACTOR: u128 = 1_000_000_000;
+
+fn load_pool(storage: &dyn Storage) -> StdResult<StabilityPool> {
+    Ok(STABILITYPOOL
+        .may_load(storage)?
+        .unwrap_or_else(|| StabilityPool {
+            total: Uint128::zero(),
+            product: Uint128::new(PRECISION),
+            scale: 0,
+            epoch: 0,
+        }))
+}
+
+/// mUSD in the pool, the most debt it can absorb.
+pub fn pool_size(storage: &dyn Storage) -> StdResult<Uint128> {
+    Ok(load_pool(storage)?.total)
+}
+
+/// Burns `debt` from the pool and hands `gain` of `collateral` to the
+/// depositors. `debt` must not be above `pool_size`.
+pub fn absorb(
+    storage: &mut dyn Storage,
+    collateral: &str,
+    debt: Uint128,
+    gain: Uint128,
+) -> StdResult<()> {
+    let mut pool = load_pool(storage)?;
+    if debt.is_zero() || pool.total.is_zero() {
+        return Ok(());
+    }
+
+    let gain_per_unit = gain.multiply_ratio(PRECISION, pool.total);
+    let marginal = gain_per_unit.multiply_ratio(pool.product, PRECISION);
+    POOLSUMS.update(
+        storage,
+        (pool.epoch, pool.scale, collateral.to_string()),
+        |sum| -> StdResult<_> { Ok(sum.unwrap_or_default() + marginal) },
+    )?;
+    POOLCOLLATERAL.update(storage, collateral.to_string(), |held| -> StdResult<_> {
+        Ok(held.unwrap_or_default() + gain)
+    })?;
+
+    // round the loss up so deposits never add up to more than the pool holds
+    let loss_per_unit = debt.multiply_ratio(PRECISION, pool.total) + Uint128::new(1);
+    if debt >= pool.total || loss_per_unit >= Uint128::new(PRECISION) {
+        pool.epoch += 1;
+        pool.scale = 0;
+        pool.product = Uint128::new(PRECISION);
+        pool.total = Uint128::zero();
+    } else {
+        let factor = Uint128::new(PRECISION) - loss_per_unit;
+        let product = pool.product.multiply_ratio(factor, PRECISION);
+        if product < Uint128::new(SCALE_FACTOR) {
+            pool.product = pool
+                .product
+                .multiply_ratio(factor * Uint128::new(SCALE_FACTOR), PRECISION);
+            pool.scale += 1;
+        } else {
+            pool.product = product;
+        }
+        pool.total -= debt;
+    }
+    STABILITYPOOL.save(storage, &pool)
+}
+
+/// What is left of a deposit after the liquidations absorbed since it was made.
+fn compounded_deposit(pool: &StabilityPool, deposit: &PoolDeposit) -> Uint128 {
+    if deposit.epoch < pool.epoch {
+        return Uint128::zero();
+    }
+    let compounded = deposit.amount.multiply_ratio(pool.product, deposit.product);
+    match pool.scale - deposit.scale {
+        0 => compounded,
+        1 => compounded / Uint128::new(SCALE_FACTOR),
+        _ => Uint128::zero(),
+    }
+}
+
+/// Collateral earned by a deposit since it was made, per collateral type.
+fn collateral_gains(
+    storage: &dyn Storage,
+    depositor: &Addr,
+    deposit: &PoolDeposit,
+) -> StdResult<Vec<(String, Uint128)>> {
+    let collaterals = COLLATERALTYPES
+        .keys(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<String>>>()?;
+    let mut gains = vec![];
+    for collateral in collaterals {
+        let snapshot = POOLSNAPSHOTS
+            .may_load(storage, (depositor.clone(), collateral.clone()))?
+            .unwrap_or_default();
+        let first = POOLSUMS
+            .may_load(storage, (deposit.epoch, deposit.scale, collateral.clone()))?
+            .unwrap_or_default()
+            - snapshot;
+        let second = POOLSUMS
+            .may_load(
+                storage,
+                (deposit.epoch, deposit.scale + 1, collateral.clone()),
+            )?
+            .unwrap_or_default()
+            / Uint128::new(SCALE_FACTOR);
+        let gain = deposit
+            .amount
+            .multiply_ratio(first + second, deposit.product);
+        if !gain.is_zero() {
+            gains.push((collateral, gain));
+        }
+    }
+    Ok(gains)
+}
+
+/// Pays out the collateral gains of `depositor` and resets its deposit, as of
+/// the current pool state, to what `change` makes of the compounded deposit.
+/// Returns the compounded deposit.
+fn settle_depositor(
+    storage: &mut dyn Storage,
+    depositor: &Addr,
+    change: impl FnOnce(Uint128) -> Result<Uint128, ContractError>,
+    mut response: Response,
+) -> Result<(Uint128, Response), ContractError> {
+    let pool = load_pool(storage)?;
+    let (compounded, gains) = match POOLDEPOSITS.may_load(storage, depositor.clone())? {
+        Some(deposit) => (
+            compounded_deposit(&pool, &deposit),
+            collateral_gains(storage, depositor, &deposit)?,
+        ),
+        None => (Uint128::zero(), vec![]),
+    };
+
+    for (collateral, gain) in gains {
+        let collateral_type = load_collateral_type(storage, &collateral)?;
+        POOLCOLLATERAL.update(storage, collateral.clone(), |held| -> StdResult<_> {
+            Ok(held.unwrap_or_default().saturating_sub(gain))
+        })?;
+        response = response
+            .add_message(send_asset(&collateral_type.denom, depositor.clone(), gain)?)
+            .add_attribute(collateral, gain);
+    }
+
+    let amount = change(compounded)?;
+    let collaterals = COLLATERALTYPES
+        .keys(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<String>>>()?;
+    if amount.is_zero() {
+        POOLDEPOSITS.remove(storage, depositor.clone());
+        for collateral in collaterals {
+            POOLSNAPSHOTS.remove(storage, (depositor.clone(), collateral));
+        }
+    } else {
+        POOLDEPOSITS.save(
+            storage,
+            depositor.clone(),
+            &PoolDeposit {
+                amount,
+                product: pool.product,
+                scale: pool.scale,
+                epoch: pool.epoch,
+            },
+        )?;
+        for collateral in collaterals {
+            let sum = POOLSUMS
+                .may_load(storage, (pool.epoch, pool.scale, collateral.clone()))?
+                .unwrap_or_default();
+            POOLSNAPSHOTS.save(storage, (depositor.clone(), collateral), &sum)?;
+        }
+    }
+    Ok((compounded, response))
+}
+
+/// Moves `amount` mUSD from the sender into the pool, paying out the
+/// collateral gains so far. Needs an mUSD allowance.
+pub fn execute_deposit_to_pool(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    if amount.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+    let token = STABLE.load(deps.storage)?;
+    let response = Response::new().add_message(WasmMsg::Execute {
+        contract_addr: token.to_string(),
+        msg: to_json_binary(&Cw20ExecuteMsg::TransferFrom {
+            owner: info.sender.to_string(),
+            recipient: env.contract.address.to_string(),
+            amount,
+        })?,
+        funds: vec![],
+    });
+    let (_, response) = settle_depositor(
+        deps.storage,
+        &info.sender,
+        |compounded| Ok(compounded + amount),
+        response,
+    )?;
+    let mut pool = load_pool(deps.storage)?;
+    pool.total += amount;
+    STABILITYPOOL.save(deps.storage, &pool)?;
+
+    Ok(response
+        .add_attribute("action", "deposit_to_pool")
+        .add_attribute("depositor", info.sender)
+        .add_attribute("amount", amount))
+}
+
+/// Takes up to `amount` of what is left of the sender's deposit out of the
+/// pool, paying out the collateral gains so far.
+pub fn execute_withdraw_from_pool(
+    deps: DepsMut,
+    info: MessageInfo,
+    amount: Uint128,
+) -> Result<Response, ContractError> {
+    if amount.is_zero() {
+        return Err(ContractError::InvalidAmount {});
+    }
+    let mut withdrawn = Uint128::zero();
+    let (_, response) = settle_depositor(
+        deps.storage,
+        &info.sender,
+        |compounded| {
+            withdrawn = amount.min(compounded);
+            if withdrawn.is_zero() {
+                return Err(ContractError::InvalidAmount {});
+            }
+            Ok(compounded - withdrawn)
+        },
+        Response::new(),
+    )?;
+    let mut pool = load_pool(deps.storage)?;
+    pool.total = pool.total.saturating_sub(withdrawn);
+    STABILITYPOOL.save(deps.storage, &pool)?;
+
+    let token = STABLE.load(deps.storage)?;
+    Ok(response
+        .add_message(send_asset(
+            &Denom::Cw20(token),
+            info.sender.clone(),
+            withdrawn,
+        )?)
+        .add_attribute("action", "withdraw_from_pool")
+        .add_attribute("depositor", info.sender)
+        .add_attribute("amount", withdrawn))
+}
+
+/// Pays out the collateral the sender's deposit earned so far.
+pub fn execute_claim_collateral_gains(
+    deps: DepsMut,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let (compounded, response) = settle_depositor(deps.storage, &info.sender, Ok, Response::new())?;
+    if compounded.is_zero() && response.messages.is_empty() {
+        return Err(ContractError::InvalidAmount {});
+    }
+
+    Ok(response
+        .add_attribute("action", "claim_collateral_gains")
+        .add_attribute("depositor", info.sender))
+}
+
+pub fn query_stability_pool(deps: Deps) -> StdResult<StabilityPoolResponse> {
+    let pool = load_pool(deps.storage)?;
+    let collateral = POOLCOLLATERAL
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (collateral, amount) = item?;
+            Ok(CollateralGainResponse { collateral, amount })
+        })
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(StabilityPoolResponse {
+        total: pool.total,
+        epoch: pool.epoch,
+        collateral,
+    })
+}
+
+pub fn query_pool_deposit(deps: Deps, depositor: String) -> StdResult<PoolDepositResponse> {
+    let depositor = deps.api.addr_validate(&depositor)?;
+    let pool = load_pool(deps.storage)?;
+    let (deposit, gains) = match POOLDEPOSITS.may_load(deps.storage, depositor.clone())? {
+        Some(deposit) => (
+            compounded_deposit(&pool, &deposit),
+            collateral_gains(deps.storage, &depositor, &deposit)?,
+        ),
+        None => (Uint128::zero(), vec![]),
+    };
+    Ok(PoolDepositResponse {
+        deposit,
+        gains: gains
+            .into_iter()
+            .map(|(collateral, amount)| CollateralGainResponse { collateral, amount })
+            .collect(),
+    })
+}
@@ -1,8 +1,10 @@
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
-use cosmwasm_std::{Addr, Uint128};
-use cw_storage_plus::{Item, Map};
+use cosmwasm_std::{Addr, Binary, Decimal, Empty, Uint128};
+use cw20::{Denom, Expiration};
+use cw_storage_plus::{Index, IndexList, IndexedMap, Item, Map, MultiIndex};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct State {
@@ -14,16 +16,482 @@ pub struct State {
 pub struct Config {
     pub owner: Addr,
     pub oracle: Addr,
-    pub denom: String,
+    /// Receives the stability fees minted by `CollectFees`.
+    pub treasury: Addr,
+    pub token_set: bool,
+    /// Oldest oracle update, in seconds, that is still accepted.
+    pub max_price_age: u64,
+    /// Largest relative move from the last accepted price that is accepted.
+    /// A rejected move goes through once that price is older than `max_price_age`.
+    pub max_price_deviation: Decimal,
+    /// Length, in seconds, of the window averaged by the TWAP that liquidations
+    /// are decided on. Zero makes the TWAP follow the spot price.
+    pub twap_window: u64,
+    /// Most mUSD that may be outstanding across all collateral types and the PSM.
+    pub debt_ceiling: Uint128,
+    /// Seconds between shutdown and the first `RedeemSettled`, left for
+    /// positions to be settled.
+    pub settlement_delay: u64,
+    /// Seconds a change queued with `UpdateConfig` waits before it can be applied.
+    pub update_delay: u64,
+    /// Fee charged on the amount of a `FlashMint`, burned and booked as surplus.
+    pub flash_fee: Decimal,
+    /// Most mUSD a single `FlashMint` may mint.
+    pub max_flash_mint: Uint128,
+}
+
+/// Last oracle price that passed validation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PriceRecord {
+    pub price: Uint128,
+    pub updated_at: u64,
+}
+
+/// Price accepted at a given time, with the running sum of price × seconds
+/// over the observations before it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Observation {
+    pub price: Uint128,
+    pub cumulative: Uint128,
+}
+
+/// Global settlement, once the owner has shut the system down.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Shutdown {
+    pub started_at: u64,
+    /// Time from which the redemption rate can be fixed.
+    pub redeemable_at: u64,
+    /// mUSD, in circulation or in savings, still to redeem for the settlement
+    /// pools and PSM reserves. Set by `FixRates` once every debt is settled.
+    pub unredeemed: Option<Uint128>,
+}
+
+/// Numbered vault holding one collateral type, opened with `OpenVault`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Position {
+    pub owner: Addr,
+    /// Name of the collateral type the vault holds.
+    pub collateral_type: String,
+    /// Collateral deposited.
+    pub collateral: Uint128,
+    /// Debt normalized against the rate index of the collateral type; see
+    /// `CollateralRate`.
+    pub debt: Uint128,
+}
+
+impl Position {
+    /// Collateral per unit of normalized debt, scaled by 1e12. All vaults of a
+    /// collateral type share price and rate index, so this orders them by
+    /// collateral ratio. Vaults without debt sort last.
+    pub fn collateral_ratio(&self) -> u128 {
+        if self.debt.is_zero() {
+            return u128::MAX;
+        }
+        self.collateral
+            .multiply_ratio(1_000_000_000_000u128, self.debt)
+            .u128()
+    }
+}
+
+/// mUSD deposited in the stability pool and the running product of the
+/// factors deposits shrank by, see `stability_pool`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct StabilityPool {
+    pub total: Uint128,
+    /// Running product P, with 18 decimals.
+    pub product: Uint128,
+    /// Times P was scaled up by 1e9 within the epoch.
+    pub scale: u64,
+    /// Times the pool was emptied by a liquidation.
+    pub epoch: u64,
+}
+
+/// Stability pool deposit as of its last change.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PoolDeposit {
+    pub amount: Uint128,
+    pub product: Uint128,
+    pub scale: u64,
+    pub epoch: u64,
+}
+
+/// Flash mint waiting for the callback of its borrower to return.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FlashMint {
+    pub borrower: Addr,
+    pub amount: Uint128,
+    pub fee: Uint128,
+}
+
+/// Savings rate and the chi index savings are normalized against, see `savings`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Savings {
+    /// Annual rate chi grows at.
+    pub rate: Decimal,
+    pub chi: Decimal,
+    pub updated_at: u64,
+    /// Normalized savings of all holders.
+    pub total: Uint128,
+}
+
+/// Base rate of the redemption fee, as last updated by a redemption.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct RedemptionRate {
+    pub base_rate: Decimal,
+    /// Time, in seconds, up to which the base rate has been decayed. It moves
+    /// in whole minutes so that no decay is lost to rounding.
+    pub updated_at: u64,
+}
+
+/// Risk parameters of one kind of collateral accepted by the vault.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CollateralType {
+    /// Asset users deposit, a native denom such as `uom` or a cw20 token.
+    pub denom: Denom,
+    /// Symbol asked from the oracle, e.g. `OM`.
+    pub oracle_symbol: String,
+    /// Health factor below which a position can be liquidated.
     pub min_threashold: Uint128,
+    /// Required collateral value in percent of the debt, e.g. 129.
     pub liquidity_threashold: Uint128,
-    pub token_set : bool
+    /// Maximum mUSD that may be minted against this collateral.
+    pub debt_ceiling: Uint128,
+    /// Annual stability fee charged on debt, e.g. 0.02 for 2%.
+    pub stability_fee: Decimal,
+    /// Share of the liquidated debt added on top when raising it in auction.
+    pub liquidation_penalty: Decimal,
+    pub auction: AuctionParams,
+}
+
+/// Shape of the descending price curve used to sell seized collateral.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AuctionParams {
+    /// Starting price as a multiple of the oracle price, e.g. 1.2.
+    pub start_multiplier: Decimal,
+    /// Seconds between two price decreases.
+    pub step: u64,
+    /// Factor applied to the price at every step, e.g. 0.99.
+    pub cut: Decimal,
+    /// Seconds after which a lot has to be reset before it can be taken.
+    pub duration: u64,
+}
+
+/// Collateral seized from a liquidated position and up for auction.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Lot {
+    /// Owner of the liquidated position, receives any collateral left over.
+    pub owner: Addr,
+    pub collateral: String,
+    /// Collateral still for sale.
+    pub amount: Uint128,
+    /// Liquidated debt not yet covered by bids.
+    pub debt: Uint128,
+    /// mUSD still to raise, the remaining debt plus penalty.
+    pub tab: Uint128,
+    pub start_price: Uint128,
+    pub started_at: u64,
+}
+
+/// Cumulative stability fee index of a collateral type. Debt owed is the
+/// normalized debt multiplied by `rate`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CollateralRate {
+    pub rate: Decimal,
+    pub last_updated: u64,
+}
+
+/// Kind of price feed a collateral type can be priced from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum OracleAdapter {
+    /// hongbai oracle contract answering `GetPrice { symbol }`.
+    Hongbai { contract: Addr, symbol: String },
+    /// Any contract answering the smart query `query` with a `price` and
+    /// optional `timestamp`.
+    Generic { contract: Addr, query: Binary },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OracleSource {
+    pub adapter: OracleAdapter,
+    /// Weight of the source when aggregating with `Aggregation::Weighted`.
+    pub weight: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregation {
+    Median,
+    Weighted,
+}
+
+/// Role that can be granted to an address. The owner and admins pass every
+/// role check.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Grants and revokes the other roles, sets the token, unpauses and shuts down.
+    Admin,
+    /// Registers collateral types and PSM assets and sets debt ceilings.
+    RiskManager,
+    /// Registers price sources.
+    OracleManager,
+    /// Pauses operations.
+    Guardian,
+    /// Sets the treasury and collects stability fees.
+    FeeCollector,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [
+        Role::Admin,
+        Role::RiskManager,
+        Role::OracleManager,
+        Role::Guardian,
+        Role::FeeCollector,
+    ];
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Role::Admin => "admin",
+            Role::RiskManager => "risk_manager",
+            Role::OracleManager => "oracle_manager",
+            Role::Guardian => "guardian",
+            Role::FeeCollector => "fee_collector",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Operation that can be paused, globally or per collateral type.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PauseOp {
+    Mint,
+    Borrow,
+    Redeem,
+    Liquidate,
+    Swap,
+}
+
+impl PauseOp {
+    pub const ALL: [PauseOp; 5] = [
+        PauseOp::Mint,
+        PauseOp::Borrow,
+        PauseOp::Redeem,
+        PauseOp::Liquidate,
+        PauseOp::Swap,
+    ];
+
+    /// Bit of the operation in a pause bitmap.
+    pub fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+impl fmt::Display for PauseOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            PauseOp::Mint => "mint",
+            PauseOp::Borrow => "borrow",
+            PauseOp::Redeem => "redeem",
+            PauseOp::Liquidate => "liquidate",
+            PauseOp::Swap => "swap",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Price sources of one collateral type.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OracleConfig {
+    pub sources: Vec<OracleSource>,
+    pub aggregation: Aggregation,
+    /// Minimum number of fresh answers needed to aggregate `sources`.
+    pub quorum: u32,
+    /// Source used on its own when `sources` do not reach the quorum.
+    pub fallback: Option<OracleAdapter>,
+}
+
+/// Stablecoin accepted by the peg stability module.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PsmAsset {
+    pub denom: Denom,
+    /// Fee taken when swapping the asset into mUSD.
+    pub tin: Decimal,
+    /// Fee taken when swapping mUSD into the asset.
+    pub tout: Decimal,
+    /// Maximum amount of the asset the module holds, and so mints against.
+    pub debt_ceiling: Uint128,
+}
+
+/// Parameter changes queued by `UpdateConfig`. Fields left `None` are kept;
+/// the per-type fields apply to `collateral`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct ConfigUpdate {
+    pub oracle: Option<String>,
+    pub treasury: Option<String>,
+    /// Ceiling on mUSD outstanding across all collateral types and the PSM.
+    pub global_debt_ceiling: Option<Uint128>,
+    pub max_price_age: Option<u64>,
+    pub max_price_deviation: Option<Decimal>,
+    pub twap_window: Option<u64>,
+    pub update_delay: Option<u64>,
+    pub savings_rate: Option<Decimal>,
+    pub flash_fee: Option<Decimal>,
+    pub max_flash_mint: Option<Uint128>,
+    pub collateral: Option<String>,
+    /// Replaces the price sources of the collateral type.
+    pub oracle_sources: Option<OracleConfig>,
+    pub min_threashold: Option<Uint128>,
+    pub liquidity_threashold: Option<Uint128>,
+    pub debt_ceiling: Option<Uint128>,
+    pub stability_fee: Option<Decimal>,
+    pub liquidation_penalty: Option<Decimal>,
+    pub auction: Option<AuctionParams>,
+}
+
+impl ConfigUpdate {
+    /// Whether the update touches the parameters of a collateral type.
+    pub fn has_collateral_fields(&self) -> bool {
+        self.oracle_sources.is_some()
+            || self.min_threashold.is_some()
+            || self.liquidity_threashold.is_some()
+            || self.debt_ceiling.is_some()
+            || self.stability_fee.is_some()
+            || self.liquidation_penalty.is_some()
+            || self.auction.is_some()
+    }
+
+    /// Writes the collateral parameters of the update into `collateral_type`.
+    pub fn apply_to(&self, collateral_type: &mut CollateralType) {
+        if let Some(min_threashold) = self.min_threashold {
+            collateral_type.min_threashold = min_threashold;
+        }
+        if let Some(liquidity_threashold) = self.liquidity_threashold {
+            collateral_type.liquidity_threashold = liquidity_threashold;
+        }
+        if let Some(debt_ceiling) = self.debt_ceiling {
+            collateral_type.debt_ceiling = debt_ceiling;
+        }
+        if let Some(stability_fee) = self.stability_fee {
+            collateral_type.stability_fee = stability_fee;
+        }
+        if let Some(liquidation_penalty) = self.liquidation_penalty {
+            collateral_type.liquidation_penalty = liquidation_penalty;
+        }
+        if let Some(auction) = &self.auction {
+            collateral_type.auction = auction.clone();
+        }
+    }
+}
+
+/// A `ConfigUpdate` waiting for its timelock.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingUpdate {
+    pub id: u64,
+    pub update: ConfigUpdate,
+    /// Time, in seconds, from which `ApplyUpdate` accepts the change.
+    pub effective_at: u64,
 }
 
 pub const STATE: Item<State> = Item::new("state");
 pub const OWNER: Item<Addr> = Item::new("owner");
 pub const STABLE: Item<Addr> = Item::new("stabletoken");
-pub const COLLATERALDEPOSITED: Map<Addr, Uint128> = Map::new("collateradeposited");
-pub const TOKENSMINTED: Map<Addr, Uint128> = Map::new("tokensminted");
+pub const COLLATERALTYPES: Map<String, CollateralType> = Map::new("collateraltypes");
+/// Id of the last vault opened.
+pub const VAULTCOUNT: Item<u64> = Item::new("vaultcount");
+/// Operators approved by each vault owner, with the expiry of the approval,
+/// keyed by owner and operator.
+pub const OPERATORS: Map<(Addr, Addr), Expiration> = Map::new("operators");
+pub const STABILITYPOOL: Item<StabilityPool> = Item::new("stabilitypool");
+pub const POOLDEPOSITS: Map<Addr, PoolDeposit> = Map::new("pooldeposits");
+/// Running sum S of collateral earned per unit deposited, by epoch, scale and
+/// collateral type.
+pub const POOLSUMS: Map<(u64, u64, String), Uint128> = Map::new("poolsums");
+/// S of each collateral type when a depositor last changed its deposit.
+pub const POOLSNAPSHOTS: Map<(Addr, String), Uint128> = Map::new("poolsnapshots");
+/// Collateral seized by the stability pool and not yet claimed.
+pub const POOLCOLLATERAL: Map<String, Uint128> = Map::new("poolcollateral");
+pub const FLASHMINT: Item<FlashMint> = Item::new("flashmint");
+pub const SAVINGS: Item<Savings> = Item::new("savings");
+/// Normalized savings per holder.
+pub const SAVINGSDEPOSITS: Map<Addr, Uint128> = Map::new("savingsdeposits");
+/// Base rate of the redemption fee.
+pub const REDEMPTIONRATE: Item<RedemptionRate> = Item::new("redemptionrate");
+pub const RATES: Map<String, CollateralRate> = Map::new("rates");
+/// Total normalized debt per collateral type.
+pub const TOTALDEBT: Map<String, Uint128> = Map::new("totaldebt");
+/// Protocol surplus: stability fees, liquidation penalties and PSM fees not yet
+/// minted to the treasury, net of bad debt.
+pub const ACCRUEDFEES: Item<Uint128> = Item::new("accruedfees");
+pub const LOTS: Map<u64, Lot> = Map::new("lots");
+pub const LOTCOUNT: Item<u64> = Item::new("lotcount");
+/// Debt that liquidations and settlement failed to cover, net of surplus.
+pub const BADDEBT: Item<Uint128> = Item::new("baddebt");
+/// Last accepted oracle price per collateral type.
+pub const LASTPRICES: Map<String, PriceRecord> = Map::new("lastprices");
+/// Price observations per collateral type, keyed by block time.
+pub const OBSERVATIONS: Map<(String, u64), Observation> = Map::new("observations");
+/// Price sources per collateral type. Types without an entry use the hongbai
+/// oracle from `Config` with their `oracle_symbol`.
+pub const ORACLESOURCES: Map<String, OracleConfig> = Map::new("oraclesources");
+pub const PSMASSETS: Map<String, PsmAsset> = Map::new("psmassets");
+/// Amount of each PSM asset held by the contract.
+pub const PSMRESERVES: Map<String, Uint128> = Map::new("psmreserves");
 pub const LIQUIDATIONTH: Item<Uint128> = Item::new("liquidationThreashold");
 pub const CONFIG: Item<Config> = Item::new("config");
+pub const SHUTDOWN: Item<Shutdown> = Item::new("shutdown");
+/// Collateral prices frozen at shutdown.
+pub const SETTLEMENTPRICES: Map<String, Uint128> = Map::new("settlementprices");
+/// Collateral taken from settled positions, paid out by `RedeemSettled`.
+pub const SETTLEDCOLLATERAL: Map<String, Uint128> = Map::new("settledcollateral");
+/// Operations paused for every collateral type, as a bitmap of `PauseOp`.
+pub const PAUSED: Item<u8> = Item::new("paused");
+/// Operations paused for a single collateral type, as a bitmap of `PauseOp`.
+pub const COLLATERALPAUSED: Map<String, u8> = Map::new("collateralpaused");
+/// Holders of each role, keyed by role name and address.
+pub const ROLES: Map<(String, Addr), Empty> = Map::new("roles");
+/// Address the owner offered ownership to, until it accepts.
+pub const PENDINGOWNER: Item<Addr> = Item::new("pendingowner");
+/// Parameter changes queued by `UpdateConfig`, by id.
+pub const PENDINGUPDATES: Map<u64, PendingUpdate> = Map::new("pendingupdates");
+pub const UPDATECOUNT: Item<u64> = Item::new("updatecount");
+
+pub struct PositionIndexes<'a> {
+    pub owner: MultiIndex<'a, Addr, Position, u64>,
+    /// Collateral type and `Position::collateral_ratio`, which redemptions walk
+    /// from the lowest ratio up.
+    pub ratio: MultiIndex<'a, (String, u128), Position, u64>,
+}
+
+impl<'a> IndexList<Position> for PositionIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<Position>> + '_> {
+        let v: Vec<&dyn Index<Position>> = vec![&self.owner, &self.ratio];
+        Box::new(v.into_iter())
+    }
+}
+
+/// Vaults by id, indexed by owner and by collateral ratio.
+pub fn positions<'a>() -> IndexedMap<'a, u64, Position, PositionIndexes<'a>> {
+    let indexes = PositionIndexes {
+        owner: MultiIndex::new(
+            |_pk, position| position.owner.clone(),
+            "positions",
+            "positions__owner",
+        ),
+        ratio: MultiIndex::new(
+            |_pk, position| {
+                (
+                    position.collateral_type.clone(),
+                    position.collateral_ratio(),
+                )
+            },
+            "positions",
+            "positions__ratio",
+        ),
+    };
+    IndexedMap::new("positions", indexes)
+}
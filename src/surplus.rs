@@ -0,0 +1,86 @@
+use cosmwasm_std::{Decimal, Deps, Env, Order, StdResult, Storage, Uint128};
+use cw20::{Cw20QueryMsg, TokenInfoResponse};
+
+use crate::msg::SystemBalanceResponse;
+use crate::rates::{current_rate, denormalize, global_debt};
+use crate::savings::{current_savings, pending_interest};
+use crate::state::{ACCRUEDFEES, BADDEBT, COLLATERALTYPES, LOTS, RATES, STABLE, TOTALDEBT};
+
+// Surplus is mUSD the protocol is owed beyond what backs the supply: stability
+// fees, liquidation penalties and PSM fees, less the interest paid on savings.
+// Bad debt is mUSD in circulation that nothing backs any more. The two cancel
+// out as they are booked, so at most one of them is non-zero.
+
+/// Books `amount` of protocol income, cancelling bad debt first. Returns the
+/// bad debt cancelled.
+pub fn book_surplus(storage: &mut dyn Storage, amount: Uint128) -> StdResult<Uint128> {
+    let surplus = ACCRUEDFEES.may_load(storage)?.unwrap_or_default();
+    let bad_debt = BADDEBT.may_load(storage)?.unwrap_or_default();
+    let cancelled = amount.min(bad_debt);
+    BADDEBT.save(storage, &(bad_debt - cancelled))?;
+    ACCRUEDFEES.save(storage, &surplus.checked_add(amount - cancelled)?)?;
+    Ok(cancelled)
+}
+
+/// Books `amount` of debt nothing backs any more, covering it from the surplus
+/// first. Returns the surplus used.
+pub fn book_bad_debt(storage: &mut dyn Storage, amount: Uint128) -> StdResult<Uint128> {
+    let surplus = ACCRUEDFEES.may_load(storage)?.unwrap_or_default();
+    let bad_debt = BADDEBT.may_load(storage)?.unwrap_or_default();
+    let covered = amount.min(surplus);
+    ACCRUEDFEES.save(storage, &(surplus - covered))?;
+    BADDEBT.save(storage, &bad_debt.checked_add(amount - covered)?)?;
+    Ok(covered)
+}
+
+/// Stability fees accrued since each collateral type was last touched, not yet
+/// booked.
+fn pending_fees(storage: &dyn Storage, env: &Env) -> StdResult<Uint128> {
+    let mut pending = Uint128::zero();
+    let collateral_types = COLLATERALTYPES
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    for (collateral, collateral_type) in collateral_types {
+        let previous = RATES
+            .may_load(storage, collateral.clone())?
+            .map(|rate| rate.rate)
+            .unwrap_or_else(Decimal::one);
+        let rate = current_rate(storage, env, &collateral, &collateral_type)?.rate;
+        let normalized = TOTALDEBT.may_load(storage, collateral)?.unwrap_or_default();
+        pending += denormalize(normalized, rate).saturating_sub(denormalize(normalized, previous));
+    }
+    Ok(pending)
+}
+
+/// Surplus and bad debt as of the current block, next to the debt backing the
+/// mUSD supply. Outside global settlement, and up to rounding,
+/// `supply + surplus + savings == total_debt + auction_debt + bad_debt`.
+pub fn query_system_balance(deps: Deps, env: Env) -> StdResult<SystemBalanceResponse> {
+    let income = ACCRUEDFEES.load(deps.storage)? + pending_fees(deps.storage, &env)?;
+    let losses = BADDEBT.load(deps.storage)? + pending_interest(deps.storage, &env)?;
+    let savings = current_savings(deps.storage, &env)?;
+
+    let mut auction_debt = Uint128::zero();
+    for item in LOTS.range(deps.storage, None, None, Order::Ascending) {
+        auction_debt += item?.1.debt;
+    }
+    let supply = match STABLE.may_load(deps.storage)? {
+        Some(token) => {
+            let token_info: TokenInfoResponse = deps
+                .querier
+                .query_wasm_smart(token, &Cw20QueryMsg::TokenInfo {})?;
+            token_info.total_supply
+        }
+        None => Uint128::zero(),
+    };
+
+    Ok(SystemBalanceResponse {
+        surplus: income.saturating_sub(losses),
+        bad_debt: losses.saturating_sub(income),
+        total_debt: global_debt(deps.storage, &env)?,
+        auction_debt,
+        savings: denormalize(savings.total, savings.chi),
+        supply,
+        fully_backed: losses <= income,
+    })
+}
@@ -0,0 +1,190 @@
+use cosmwasm_std::{Decimal, Deps, DepsMut, Env, MessageInfo, Order, Response, StdResult};
+
+use crate::access::ensure_role;
+use crate::contract::{load_collateral_type, validate_collateral_type};
+use crate::error::ContractError;
+use crate::msg::PendingUpdatesResponse;
+use crate::oracle::validate_oracle_config;
+use crate::rates::accrue;
+use crate::savings::set_savings_rate;
+use crate::settlement::ensure_live;
+use crate::state::{
+    ConfigUpdate, PendingUpdate, Role, COLLATERALTYPES, CONFIG, ORACLESOURCES, PENDINGUPDATES,
+    UPDATECOUNT,
+};
+
+fn invalid(reason: &str) -> ContractError {
+    ContractError::InvalidConfigUpdate {
+        reason: reason.to_string(),
+    }
+}
+
+fn validate_update(deps: Deps, update: &ConfigUpdate) -> Result<(), ContractError> {
+    if let Some(oracle) = &update.oracle {
+        deps.api.addr_validate(oracle)?;
+    }
+    if let Some(treasury) = &update.treasury {
+        deps.api.addr_validate(treasury)?;
+    }
+    if let Some(oracle_config) = &update.oracle_sources {
+        validate_oracle_config(deps, oracle_config)?;
+    }
+    match &update.collateral {
+        Some(collateral) => {
+            let mut collateral_type = load_collateral_type(deps.storage, collateral)?;
+            if !update.has_collateral_fields() {
+                return Err(invalid("collateral given without parameters to change"));
+            }
+            update.apply_to(&mut collateral_type);
+            validate_collateral_type(deps, &collateral_type)?;
+        }
+        None if update.has_collateral_fields() => {
+            return Err(invalid("collateral parameters need a collateral"));
+        }
+        None => {}
+    }
+    if update.flash_fee.map_or(false, |fee| fee >= Decimal::one()) {
+        return Err(invalid("flash_fee must be below 1"));
+    }
+    if update
+        .savings_rate
+        .map_or(false, |rate| rate >= Decimal::one())
+    {
+        return Err(invalid("savings_rate must be below 1"));
+    }
+    if *update == ConfigUpdate::default() {
+        return Err(invalid("nothing to update"));
+    }
+    Ok(())
+}
+
+/// Queues `update` until `update_delay` has passed. Oracle and price source
+/// changes also need the oracle manager role, treasury changes the fee
+/// collector role.
+pub fn execute_update_config(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    update: ConfigUpdate,
+) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    ensure_role(deps.storage, &info.sender, Role::RiskManager)?;
+    if update.oracle.is_some() || update.oracle_sources.is_some() {
+        ensure_role(deps.storage, &info.sender, Role::OracleManager)?;
+    }
+    if update.treasury.is_some() {
+        ensure_role(deps.storage, &info.sender, Role::FeeCollector)?;
+    }
+    validate_update(deps.as_ref(), &update)?;
+
+    let config = CONFIG.load(deps.storage)?;
+    let id = UPDATECOUNT.may_load(deps.storage)?.unwrap_or_default() + 1;
+    UPDATECOUNT.save(deps.storage, &id)?;
+    let pending = PendingUpdate {
+        id,
+        update,
+        effective_at: env.block.time.seconds() + config.update_delay,
+    };
+    PENDINGUPDATES.save(deps.storage, id, &pending)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "update_config")
+        .add_attribute("update_id", id.to_string())
+        .add_attribute("effective_at", pending.effective_at.to_string()))
+}
+
+/// Drops a queued update before it becomes effective.
+pub fn execute_cancel_update(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    id: u64,
+) -> Result<Response, ContractError> {
+    ensure_role(deps.storage, &info.sender, Role::RiskManager)?;
+    let pending = PENDINGUPDATES
+        .may_load(deps.storage, id)?
+        .ok_or(ContractError::UnknownUpdate { id })?;
+    if env.block.time.seconds() >= pending.effective_at {
+        return Err(ContractError::UpdateEffective {
+            effective_at: pending.effective_at,
+        });
+    }
+    PENDINGUPDATES.remove(deps.storage, id);
+
+    Ok(Response::new()
+        .add_attribute("action", "cancel_update")
+        .add_attribute("update_id", id.to_string()))
+}
+
+/// Applies a queued update once its delay has passed. Open to anyone.
+pub fn execute_apply_update(deps: DepsMut, env: Env, id: u64) -> Result<Response, ContractError> {
+    ensure_live(deps.storage)?;
+    let pending = PENDINGUPDATES
+        .may_load(deps.storage, id)?
+        .ok_or(ContractError::UnknownUpdate { id })?;
+    if env.block.time.seconds() < pending.effective_at {
+        return Err(ContractError::UpdateNotReady {
+            effective_at: pending.effective_at,
+        });
+    }
+    PENDINGUPDATES.remove(deps.storage, id);
+    let update = pending.update;
+
+    let mut config = CONFIG.load(deps.storage)?;
+    if let Some(oracle) = &update.oracle {
+        config.oracle = deps.api.addr_validate(oracle)?;
+    }
+    if let Some(treasury) = &update.treasury {
+        config.treasury = deps.api.addr_validate(treasury)?;
+    }
+    if let Some(global_debt_ceiling) = update.global_debt_ceiling {
+        config.debt_ceiling = global_debt_ceiling;
+    }
+    if let Some(max_price_age) = update.max_price_age {
+        config.max_price_age = max_price_age;
+    }
+    if let Some(max_price_deviation) = update.max_price_deviation {
+        config.max_price_deviation = max_price_deviation;
+    }
+    if let Some(twap_window) = update.twap_window {
+        config.twap_window = twap_window;
+    }
+    if let Some(update_delay) = update.update_delay {
+        config.update_delay = update_delay;
+    }
+    if let Some(flash_fee) = update.flash_fee {
+        config.flash_fee = flash_fee;
+    }
+    if let Some(max_flash_mint) = update.max_flash_mint {
+        config.max_flash_mint = max_flash_mint;
+    }
+    CONFIG.save(deps.storage, &config)?;
+    if let Some(savings_rate) = update.savings_rate {
+        set_savings_rate(deps.storage, &env, savings_rate)?;
+    }
+
+    if let Some(collateral) = &update.collateral {
+        let mut collateral_type = load_collateral_type(deps.storage, collateral)?;
+        // settle interest at the old fee before it changes
+        accrue(deps.storage, &env, collateral, &collateral_type)?;
+        update.apply_to(&mut collateral_type);
+        // updates applied in between may have changed the other parameters
+        validate_collateral_type(deps.as_ref(), &collateral_type)?;
+        if let Some(oracle_config) = &update.oracle_sources {
+            ORACLESOURCES.save(deps.storage, collateral.clone(), oracle_config)?;
+        }
+        COLLATERALTYPES.save(deps.storage, collateral.clone(), &collateral_type)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "apply_update")
+        .add_attribute("update_id", id.to_string()))
+}
+
+pub fn query_pending_updates(deps: Deps) -> StdResult<PendingUpdatesResponse> {
+    let updates = PENDINGUPDATES
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, pending)| pending))
+        .collect::<StdResult<Vec<_>>>()?;
+    Ok(PendingUpdatesResponse { updates })
+}
@@ -0,0 +1,102 @@
+use cosmwasm_std::{Env, Order, StdResult, Storage, Uint128};
+use cw_storage_plus::Bound;
+
+use crate::state::{Observation, OBSERVATIONS};
+
+/// Appends `price` to the observations of `collateral` and drops the ones that
+/// are no longer needed to average over the last `window` seconds. Only the
+/// first price of a block is kept, so it cannot be moved within the block.
+pub fn record_observation(
+    storage: &mut dyn Storage,
+    env: &Env,
+    collateral: &str,
+    price: Uint128,
+    window: u64,
+) -> StdResult<()> {
+    let now = env.block.time.seconds();
+    let observations = OBSERVATIONS.prefix(collateral.to_string());
+
+    let cumulative = match observations
+        .range(storage, None, None, Order::Descending)
+        .next()
+        .transpose()?
+    {
+        Some((timestamp, _)) if timestamp >= now => return Ok(()),
+        Some((timestamp, last)) => last.cumulative + last.price * Uint128::from(now - timestamp),
+        None => Uint128::zero(),
+    };
+    OBSERVATIONS.save(
+        storage,
+        (collateral.to_string(), now),
+        &Observation { price, cumulative },
+    )?;
+
+    // the newest observation at or before the window start is still needed
+    let start = now.saturating_sub(window);
+    let expired = observations
+        .keys(
+            storage,
+            None,
+            Some(Bound::inclusive(start)),
+            Order::Descending,
+        )
+        .skip(1)
+        .collect::<StdResult<Vec<u64>>>()?;
+    for timestamp in expired {
+        OBSERVATIONS.remove(storage, (collateral.to_string(), timestamp));
+    }
+    Ok(())
+}
+
+/// Time-weighted average price of `collateral` over the last `window` seconds,
+/// or over the observed history when it is shorter. Each observation counts
+/// until the next one, so the price recorded in the current block carries no
+/// weight yet. `None` when nothing was observed.
+pub fn twap_price(
+    storage: &dyn Storage,
+    env: &Env,
+    collateral: &str,
+    window: u64,
+) -> StdResult<Option<Uint128>> {
+    let now = env.block.time.seconds();
+    let observations = OBSERVATIONS.prefix(collateral.to_string());
+
+    let (last_timestamp, last) = match observations
+        .range(storage, None, None, Order::Descending)
+        .next()
+        .transpose()?
+    {
+        Some(last) => last,
+        None => return Ok(None),
+    };
+
+    let start = now.saturating_sub(window);
+    let anchor = observations
+        .range(
+            storage,
+            None,
+            Some(Bound::inclusive(start)),
+            Order::Descending,
+        )
+        .next()
+        .transpose()?;
+    let (anchor_timestamp, anchor) = match anchor {
+        Some(anchor) => anchor,
+        None => observations
+            .range(storage, None, None, Order::Ascending)
+            .next()
+            .transpose()?
+            .unwrap_or((last_timestamp, last.clone())),
+    };
+
+    let from = start.max(anchor_timestamp);
+    if now <= from {
+        return Ok(Some(last.price));
+    }
+    let cumulative_now =
+        last.cumulative + last.price * Uint128::from(now.saturating_sub(last_timestamp));
+    let cumulative_from = anchor.cumulative + anchor.price * Uint128::from(from - anchor_timestamp);
+    Ok(Some(
+        (cumulative_now - cumulative_from) / Uint128::from(now - from),
+    ))
+}